@@ -0,0 +1,97 @@
+use actix_web::{route, web, HttpRequest};
+use jwt_simple::algorithms::RS256KeyPair;
+
+use crate::auth_url::handle_forward_auth::check;
+use crate::error::Response;
+
+/// Envoy HTTP `ext_authz` with `path_prefix: /auth-url/ext-authz`: the original request
+/// comes with its own method, path, `Host` and `X-Forwarded-Proto`
+#[route("/auth-url/ext-authz{path:.*}", method = "GET", method = "HEAD", method = "POST", method = "PUT", method = "PATCH", method = "DELETE", method = "OPTIONS")]
+pub async fn ext_authz(req: HttpRequest, db: web::Data<reindeer::Db>, jwt_keypair: web::Data<RS256KeyPair>, path: web::Path<String>) -> Response {
+	let path = if path.starts_with('/') { path.into_inner() } else { format!("/{}", path) };
+	let forwarded_uri = if req.query_string().is_empty() {
+		path
+	} else {
+		format!("{}?{}", path, req.query_string())
+	};
+
+	check(&req, &db, &jwt_keypair, &forwarded_uri).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::token::{ProxyCookieToken, SessionToken};
+	use crate::utils::tests::*;
+	use crate::{PROXIED_COOKIE, SCOPED_SESSION_COOKIE};
+
+	use actix_web::cookie::Cookie;
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_ext_authz() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(RS256KeyPair::generate(2048).unwrap().with_key_id("default")))
+				.service(ext_authz)
+		)
+		.await;
+
+		let req = actix_test::TestRequest::post()
+			.uri("/auth-url/ext-authz/page?a=1")
+			.insert_header(("Host", "localhost:8080"))
+			.insert_header(("X-Forwarded-Proto", "http"))
+			.insert_header(("Accept", "text/html"))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert_eq!(resp.headers().get("Location").unwrap(), "http://localhost:8080/login?rd=http%3A%2F%2Flocalhost%3A8080%2Fpage%3Fa%3D1");
+
+		// The one-time code also works from the proxy cookie, like with `auth-url`
+		let session = SessionToken::new(db, user.clone(), None, None).await.unwrap();
+		let code = ProxyCookieToken::new(db, user, Some(session.code), Some("http://localhost:8080/".to_string())).await.unwrap().code;
+		let req = actix_test::TestRequest::get()
+			.uri("/auth-url/ext-authz/page")
+			.insert_header(("Host", "localhost:8080"))
+			.insert_header(("X-Forwarded-Proto", "http"))
+			.cookie(Cookie::new(PROXIED_COOKIE, code))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(resp.headers().get("X-Auth-User").unwrap(), "valid");
+		let cookie = resp.response().cookies().find(|c| c.name() == SCOPED_SESSION_COOKIE).unwrap().into_owned();
+
+		let req = actix_test::TestRequest::delete()
+			.uri("/auth-url/ext-authz")
+			.insert_header(("Host", "localhost:8080"))
+			.insert_header(("X-Forwarded-Proto", "http"))
+			.cookie(cookie.clone())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(resp.headers().get("X-Auth-Realms").unwrap(), "example");
+
+		// A client supplied X-Original-URL can't move the session or the public rules to another origin
+		let spoofed = |path: &str| actix_test::TestRequest::get()
+			.uri(&format!("/auth-url/ext-authz{}", path))
+			.insert_header(("Host", "grafana.localhost:3000"))
+			.insert_header(("X-Forwarded-Proto", "http"))
+			.insert_header(("X-Original-URL", "http://localhost:8080/"));
+
+		let req = spoofed("/admin").cookie(cookie.clone()).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		let req = spoofed("/admin").cookie(cookie).insert_header(("Accept", "text/html")).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+
+		let req = spoofed("/public/style.css").to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+	}
+}
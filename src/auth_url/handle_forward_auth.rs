@@ -0,0 +1,201 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use jwt_simple::algorithms::RS256KeyPair;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Response;
+use crate::auth_url::check_request;
+use crate::token::{ProxyCookieToken, ScopedSessionToken};
+use crate::utils::{get_forwarded_origin, get_url_origin};
+use crate::{CONFIG, SCOPED_SESSION_COOKIE};
+
+/// Where `ScopedLogin::get_redirect_url` sends the user back to with the one-time code
+const AUTH_CODE_PATH: &str = "/__magicentry_auth_code";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AuthCode {
+	pub code: String,
+}
+
+/// Exchanges the one-time code for a scoped session cookie on the domain of the service,
+/// which the nginx `auth-url` setup does with a server snippet instead
+async fn exchange_code(db: &reindeer::Db, origin: &str, code: &str) -> Response {
+	let Ok(token) = ProxyCookieToken::from_code(db, &code.to_string()).await else {
+		return Ok(HttpResponse::Unauthorized().finish())
+	};
+
+	let scope = urlencoding::decode(&token.metadata.clone().unwrap_or_default())
+		.map(|s| s.to_string())
+		.unwrap_or_default();
+	if get_url_origin(&scope).as_deref() != Some(origin) {
+		warn!("Invalid scope for forward auth code: {} vs {}", origin, &scope);
+		return Ok(HttpResponse::Unauthorized().finish())
+	}
+
+	let scoped_session = ScopedSessionToken::new(db, token.user, token.bound_to.clone(), Some(origin.to_string())).await?;
+	info!("New scoped session for: {}", origin);
+
+	// Back to wherever the user was going before logging in
+	Ok(HttpResponse::Found()
+		.append_header((header::LOCATION, scope))
+		.cookie(Cookie::build(SCOPED_SESSION_COOKIE, scoped_session.code)
+			.path("/")
+			.http_only(true)
+			.secure(true)
+			.same_site(SameSite::Lax)
+			.finish())
+		.finish())
+}
+
+/// The check shared by the proxies that send every response but a 2xx back to the browser,
+/// so the login redirect and the code exchange happen here. The `forwarded_uri` is the
+/// path and query of the original request.
+pub async fn check(req: &HttpRequest, db: &reindeer::Db, jwt_keypair: &RS256KeyPair, forwarded_uri: &str) -> Response {
+	let origin = get_forwarded_origin(req)?;
+
+	if let Some(query) = forwarded_uri.strip_prefix(AUTH_CODE_PATH).and_then(|q| q.strip_prefix('?')) {
+		if let Ok(auth_code) = serde_qs::from_str::<AuthCode>(query) {
+			return exchange_code(db, &origin, &auth_code.code).await;
+		}
+	}
+
+	let method = req.headers()
+		.get("X-Forwarded-Method")
+		.and_then(|h| h.to_str().ok())
+		.unwrap_or(req.method().as_str());
+	if let Some(response) = check_request(db, jwt_keypair, req, &origin, forwarded_uri, method).await {
+		return response;
+	}
+
+	// Only browsers can follow the redirect to the login page
+	let is_browser = req.headers()
+		.get(header::ACCEPT)
+		.and_then(|h| h.to_str().ok())
+		.is_some_and(|accept| accept.contains("text/html"));
+	if !is_browser {
+		return Ok(HttpResponse::Unauthorized().finish())
+	}
+
+	let external_url = CONFIG.read().await.external_url.clone();
+	let target = format!("{}{}", origin, forwarded_uri);
+	Ok(HttpResponse::Found()
+		.append_header((header::LOCATION, format!("{}/login?rd={}", external_url, urlencoding::encode(&target))))
+		.finish())
+}
+
+/// Traefik ForwardAuth and Caddy `forward_auth`: the original request is described by the
+/// `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Uri` headers
+#[get("/auth-url/forward-auth")]
+pub async fn forward_auth(req: HttpRequest, db: web::Data<reindeer::Db>, jwt_keypair: web::Data<RS256KeyPair>) -> Response {
+	let forwarded_uri = req.headers()
+		.get("X-Forwarded-Uri")
+		.and_then(|h| h.to_str().ok())
+		.unwrap_or("/")
+		.to_string();
+
+	check(&req, &db, &jwt_keypair, &forwarded_uri).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::token::SessionToken;
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_forward_auth() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(RS256KeyPair::generate(2048).unwrap().with_key_id("default")))
+				.service(forward_auth)
+		)
+		.await;
+
+		let forwarded = |uri: &str| actix_test::TestRequest::get()
+			.uri("/auth-url/forward-auth")
+			.insert_header(("X-Forwarded-Proto", "http"))
+			.insert_header(("X-Forwarded-Host", "localhost:8080"))
+			.insert_header(("X-Forwarded-Uri", uri.to_string()));
+
+		// Browsers are sent to log in, with the original URL to come back to
+		let req = forwarded("/page?a=1").insert_header((header::ACCEPT, "text/html,*/*")).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert_eq!(resp.headers().get("Location").unwrap(), "http://localhost:8080/login?rd=http%3A%2F%2Flocalhost%3A8080%2Fpage%3Fa%3D1");
+
+		let req = forwarded("/page?a=1").to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		// After the login, the code is exchanged for a scoped session cookie
+		let session = SessionToken::new(db, user.clone(), None, None).await.unwrap();
+		let code = ProxyCookieToken::new(db, user, Some(session.code), Some("http://localhost:8080/page?a=1".to_string())).await.unwrap().code;
+		let req = forwarded(&format!("/__magicentry_auth_code?code={}", code)).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert_eq!(resp.headers().get("Location").unwrap(), "http://localhost:8080/page?a=1");
+		let cookie = resp.response().cookies().find(|c| c.name() == SCOPED_SESSION_COOKIE).unwrap().into_owned();
+
+		let req = forwarded("/page?a=1").cookie(cookie.clone()).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(resp.headers().get("X-Auth-Email").unwrap(), "valid@example.com");
+		assert!(resp.headers().contains_key("X-Auth-Jwt-Assertion"));
+
+		// The rules of the scope apply on top of the session
+		let req = forwarded("/admin/users").cookie(cookie.clone()).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+		let req = forwarded("/public/style.css").to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+
+		let req = forwarded("/public/%2e%2e/page").to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		let req = forwarded("/api/health").insert_header(("X-Forwarded-Method", "POST")).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		// The scoped session is only good for its own origin
+		let req = actix_test::TestRequest::get()
+			.uri("/auth-url/forward-auth")
+			.insert_header(("X-Forwarded-Proto", "https"))
+			.insert_header(("X-Forwarded-Host", "other.example.com"))
+			.cookie(cookie.clone())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		// The origin comes from the headers the proxy sets, never from X-Original-URL
+		let spoofed = |uri: &str| actix_test::TestRequest::get()
+			.uri("/auth-url/forward-auth")
+			.insert_header(("X-Forwarded-Proto", "http"))
+			.insert_header(("X-Forwarded-Host", "grafana.localhost:3000"))
+			.insert_header(("X-Forwarded-Uri", uri.to_string()))
+			.insert_header(("X-Original-URL", "http://localhost:8080/"));
+
+		let req = spoofed("/admin").cookie(cookie.clone()).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		let req = spoofed("/admin").cookie(cookie).insert_header((header::ACCEPT, "text/html")).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert_eq!(resp.headers().get("Location").unwrap(), "http://localhost:8080/login?rd=http%3A%2F%2Fgrafana.localhost%3A3000%2Fadmin");
+
+		let req = spoofed("/public/style.css").to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+	}
+}
@@ -3,9 +3,11 @@ use actix_web::{get, web, HttpResponse};
 use log::info;
 use serde::{Deserialize, Serialize};
 
+use crate::auth_url::user_headers;
 use crate::error::Response;
 use crate::token::{ProxyCookieToken, ScopedSessionToken};
-use crate::{CONFIG, SCOPED_SESSION_COOKIE};
+use crate::utils::get_url_origin;
+use crate::SCOPED_SESSION_COOKIE;
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct ProxiedRewrite {
@@ -32,12 +34,11 @@ async fn response(session: Session, db: web::Data<reindeer::Db>, proxied_rewrite
 	info!("New scoped session for: {:?}", &token.metadata);
 	session.insert(SCOPED_SESSION_COOKIE, scoped_session.code)?;
 
-	let config = CONFIG.read().await;
-	Ok(HttpResponse::Ok()
-		// TODO: Add realm
-		.append_header((config.auth_url_email_header.as_str(), scoped_session.user.email.clone()))
-		.append_header((config.auth_url_user_header.as_str(), scoped_session.user.username.clone()))
-		.append_header((config.auth_url_name_header.as_str(), scoped_session.user.name.clone()))
-		.append_header((config.auth_url_realms_header.as_str(), scoped_session.user.realms.join(",")))
-		.finish())
+	let origin = scoped_session.metadata.as_deref().and_then(get_url_origin);
+	let mut response = HttpResponse::Ok();
+	for header in user_headers(&scoped_session.user, origin.as_deref()).await? {
+		response.append_header(header);
+	}
+
+	Ok(response.finish())
 }
@@ -1,41 +1,63 @@
-use actix_web::cookie::Cookie;
 use actix_web::{get, web, HttpRequest, HttpResponse};
+use jwt_simple::algorithms::RS256KeyPair;
 
+use crate::auth_url::check_request;
 use crate::error::Response;
-use crate::token::ScopedSessionToken;
-use crate::{CONFIG, SCOPED_SESSION_COOKIE};
+use crate::utils::get_request_origin;
 
+/// ingress-nginx `auth-url`: the original request is described by `X-Original-URL` and `X-Original-Method`
 #[get("/auth-url/status")]
-async fn status(req: HttpRequest, db: web::Data<reindeer::Db>) -> Response {
-	let (token, cookie): (ScopedSessionToken, Option<Cookie<'_>>) = if let Ok(Some(token)) = ScopedSessionToken::from_session(&db, &req).await {
-		#[cfg(debug_assertions)]
-		println!("Found scoped session from proxy cookie: {:?}", &token.code);
-		(token, None)
-	} else if let Ok(Some(token)) = ScopedSessionToken::from_proxy_cookie(&db, &req).await {
-		let code = token.code.clone();
-		#[cfg(debug_assertions)]
-		println!("Setting proxied cookie: {:?}", &code);
-		(token, Some(Cookie::build(SCOPED_SESSION_COOKIE, code)
-			.path("/")
-			.http_only(true)
-			.secure(true)
-			// .expires(expiry)
-			.finish()))
-	} else {
-		return Ok(HttpResponse::Unauthorized().finish())
+async fn status(req: HttpRequest, db: web::Data<reindeer::Db>, jwt_keypair: web::Data<RS256KeyPair>) -> Response {
+	let origin = get_request_origin(&req)?;
+	let header = |name: &str| req.headers().get(name).and_then(|h| h.to_str().ok());
+	// Not parsed as an `Uri`, which rejects characters that browsers send as they are (e.g. `<` in the query),
+	// the query and fragment are stripped by `normalize_path` anyway
+	let url = header("X-Original-URL").unwrap_or("/");
+	let path = match url.split_once("://") {
+		Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+		None => url,
 	};
+	let method = header("X-Original-Method").unwrap_or("GET");
 
-	let mut response_builder = HttpResponse::Ok();
-	let config = CONFIG.read().await;
-	let response = response_builder
-		.insert_header((config.auth_url_email_header.as_str(), token.user.email.clone()))
-		.insert_header((config.auth_url_user_header.as_str(), token.user.username.clone()))
-		.insert_header((config.auth_url_name_header.as_str(), token.user.name.clone()))
-		.insert_header((config.auth_url_realms_header.as_str(), token.user.realms.join(",")));
-
-	if let Some(cookie) = cookie {
-		Ok(response.cookie(cookie).finish())
-	} else {
-		Ok(response.finish())
+	match check_request(&db, &jwt_keypair, &req, &origin, path, method).await {
+		Some(response) => response,
+		None => Ok(HttpResponse::Unauthorized().finish()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_status_rules() {
+		let db = &db_connect().await;
+		crate::config::ConfigFile::reload().await.unwrap();
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(RS256KeyPair::generate(2048).unwrap().with_key_id("default")))
+				.service(status)
+		)
+		.await;
+
+		let original = |url: &str| actix_test::TestRequest::get()
+			.uri("/auth-url/status")
+			.insert_header(("X-Original-URL", url.to_string()))
+			.to_request();
+
+		let resp = actix_test::call_service(&mut app, original("http://localhost:8080/public/style.css?v=<1>")).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+
+		// Unparsable URLs are still checked against their own path
+		let resp = actix_test::call_service(&mut app, original("http://localhost:8080/admin?x=<")).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		let resp = actix_test::call_service(&mut app, original("http://localhost:8080/public/..;/admin")).await;
+		assert_eq!(resp.status(), StatusCode::FORBIDDEN);
 	}
 }
@@ -1,5 +1,19 @@
+use std::collections::BTreeMap;
+
+use actix_web::cookie::Cookie;
+use actix_web::{HttpRequest, HttpResponse};
+use jwt_simple::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::error::{AppErrorKind, Response, Result};
+use crate::oidc::handle_token::{JWTData, JWTType};
+use crate::token::ScopedSessionToken;
+use crate::user::User;
+use crate::utils::get_url_origin;
+use crate::{CONFIG, SCOPED_SESSION_COOKIE};
+
+pub mod handle_ext_authz;
+pub mod handle_forward_auth;
 pub mod handle_status;
 pub mod handle_response;
 
@@ -7,4 +21,269 @@ pub mod handle_response;
 pub struct AuthUrlScope {
 	pub origin: String,
 	pub realms: Vec<String>,
+	/// Exceptions for parts of the origin, the first matching rule applies
+	#[serde(default)]
+	pub rules: Vec<AuthUrlRule>,
+	/// Replaces the global `auth_url_*_header` headers for this origin. The values are
+	/// handlebars templates of the `username`, `email`, `name` and `realms` of the user
+	#[serde(default)]
+	pub headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AuthUrlRule {
+	/// Either an exact path (`/api/health`) or a prefix ending with `/**` (`/public/**`)
+	pub path: String,
+	/// The HTTP methods the rule applies to, all of them if empty
+	#[serde(default)]
+	pub methods: Vec<String>,
+	/// Skip the authentication altogether
+	#[serde(default)]
+	pub public: bool,
+	/// The realms the user needs, instead of the ones of the scope
+	#[serde(default)]
+	pub realms: Vec<String>,
+}
+
+impl AuthUrlRule {
+	pub fn matches(&self, path: &str, method: &str) -> bool {
+		let path_matches = match self.path.strip_suffix("/**") {
+			Some(prefix) => path == prefix || path.starts_with(&format!("{}/", prefix)),
+			None => path == self.path,
+		};
+
+		path_matches && (self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+	}
+}
+
+/// Percent-decodes the path and resolves its `.` and `..` segments, so that
+/// e.g. `/public/../admin` can't pass for a public path.
+/// Returns `None` for the paths that backends could resolve differently: backslashes,
+/// which some treat as `/`, and dot segments with parameters like `/public/..;/admin`,
+/// which Tomcat, Spring or IIS resolve to `/admin`
+pub fn normalize_path(path: &str) -> Option<String> {
+	let path = path.split(['?', '#']).next().unwrap_or_default();
+	let decoded = urlencoding::decode(path).map(|p| p.to_string()).unwrap_or_else(|_| path.to_string());
+	if decoded.contains('\\') {
+		return None;
+	}
+
+	let mut segments = Vec::new();
+	for segment in decoded.split('/') {
+		match segment {
+			"" | "." => {},
+			".." => { segments.pop(); },
+			segment if matches!(segment.split(';').next(), Some(".") | Some("..")) => return None,
+			segment => segments.push(segment),
+		}
+	}
+
+	Some(format!("/{}", segments.join("/")))
+}
+
+/// The rule of the scope of the origin that applies to the normalized path, if any
+pub async fn get_rule(origin: &str, path: &str, method: &str) -> Option<AuthUrlRule> {
+	let config = CONFIG.read().await;
+
+	config.auth_url_scopes
+		.iter()
+		.find(|s| s.origin == origin)?
+		.rules
+		.iter()
+		.find(|r| r.matches(path, method))
+		.cloned()
+}
+
+/// Finds the scoped session of the proxied request, either from its own cookie
+/// or from the one-time code of the proxy cookie, in which case the cookie of
+/// the new scoped session is returned as well
+pub async fn get_scoped_session(db: &reindeer::Db, req: &HttpRequest, origin: &str) -> Option<(ScopedSessionToken, Option<Cookie<'static>>)> {
+	if let Ok(Some(token)) = ScopedSessionToken::from_session(db, req, origin).await {
+		#[cfg(debug_assertions)]
+		println!("Found scoped session from proxy cookie: {:?}", &token.code);
+		Some((token, None))
+	} else if let Ok(Some(token)) = ScopedSessionToken::from_proxy_cookie(db, req, origin).await {
+		let code = token.code.clone();
+		#[cfg(debug_assertions)]
+		println!("Setting proxied cookie: {:?}", &code);
+		Some((token, Some(Cookie::build(SCOPED_SESSION_COOKIE, code)
+			.path("/")
+			.http_only(true)
+			.secure(true)
+			// .expires(expiry)
+			.finish())))
+	} else {
+		None
+	}
+}
+
+/// Checks the proxied request against the rules of its scope and its scoped session.
+/// Returns `None` when the user has to log in.
+pub async fn check_request(db: &reindeer::Db, jwt_keypair: &RS256KeyPair, req: &HttpRequest, origin: &str, path: &str, method: &str) -> Option<Response> {
+	let Some(path) = normalize_path(path) else {
+		log::warn!("Refusing the ambiguous path {} of {}", path, origin);
+		return Some(Ok(HttpResponse::Forbidden().finish()));
+	};
+
+	let rule = get_rule(origin, &path, method).await;
+	let scoped_session = get_scoped_session(db, req, origin).await;
+
+	match (rule, scoped_session) {
+		(Some(rule), Some((token, _))) if !rule.public && !rule.realms.is_empty() && !token.user.has_any_realm(&rule.realms) => {
+			log::warn!("{} is not allowed to {} {}{}", &token.user.email, method, origin, path);
+			Some(Ok(HttpResponse::Forbidden().finish()))
+		},
+		(_, Some((token, cookie))) => Some(authenticated_response(&token, jwt_keypair, cookie).await),
+		(Some(rule), None) if rule.public => Some(Ok(HttpResponse::Ok().finish())),
+		(_, None) => None,
+	}
+}
+
+/// The headers describing the user to the application behind the origin,
+/// either the ones of its scope or the global ones
+pub async fn user_headers(user: &User, origin: Option<&str>) -> Result<Vec<(String, String)>> {
+	let config = CONFIG.read().await;
+	let scope_headers = origin
+		.and_then(|origin| config.auth_url_scopes.iter().find(|s| s.origin == origin))
+		.map(|scope| &scope.headers)
+		.filter(|headers| !headers.is_empty());
+
+	let Some(scope_headers) = scope_headers else {
+		return Ok(vec![
+			(config.auth_url_email_header.clone(), user.email.clone()),
+			(config.auth_url_user_header.clone(), user.username.clone()),
+			(config.auth_url_name_header.clone(), user.name.clone()),
+			(config.auth_url_realms_header.clone(), user.realms.join(",")),
+		]);
+	};
+
+	let mut handlebars = handlebars::Handlebars::new();
+	handlebars.register_escape_fn(handlebars::no_escape);
+	handlebars.set_strict_mode(true);
+
+	let mut data = BTreeMap::new();
+	data.insert("username", user.username.clone());
+	data.insert("email", user.email.clone());
+	data.insert("name", user.name.clone());
+	data.insert("realms", user.realms.join(","));
+
+	scope_headers
+		.iter()
+		.map(|(name, template)| Ok((name.clone(), handlebars.render_template(template, &data)?)))
+		.collect()
+}
+
+/// A short-lived JWT asserting the user to the application behind the origin,
+/// so that it can tell requests that didn't go through the proxy
+pub async fn jwt_assertion(user: &User, origin: &str, jwt_keypair: &RS256KeyPair) -> Result<String> {
+	let config = CONFIG.read().await;
+
+	let mut claims = BTreeMap::new();
+	claims.insert("email".to_string(), user.email.clone().into());
+	claims.insert("name".to_string(), user.name.clone().into());
+	claims.insert("preferred_username".to_string(), user.username.clone().into());
+	claims.insert("realms".to_string(), user.realms.clone().into());
+	let jwt_data = JWTData {
+		user: user.email.clone(),
+		audience: vec![origin.to_string()],
+		token_type: Some(JWTType::AuthUrl),
+		claims,
+		..JWTData::new(config.external_url.trim_end_matches('/').to_string())
+	};
+
+	let duration = config.auth_url_jwt_duration
+		.num_milliseconds()
+		.try_into()
+		.map_err(|_| AppErrorKind::InvalidDuration)?;
+	let claims = Claims::with_custom_claims(jwt_data, Duration::from_millis(duration));
+
+	Ok(jwt_keypair.sign(claims)?)
+}
+
+/// The response of the proxy checks for authenticated requests, with the user in the configured headers
+pub async fn authenticated_response(token: &ScopedSessionToken, jwt_keypair: &RS256KeyPair, cookie: Option<Cookie<'static>>) -> Response {
+	let origin = token.metadata.as_deref().and_then(get_url_origin);
+	let mut response = HttpResponse::Ok();
+	for header in user_headers(&token.user, origin.as_deref()).await? {
+		response.insert_header(header);
+	}
+
+	let jwt_header = CONFIG.read().await.auth_url_jwt_header.clone();
+	if let (false, Some(origin)) = (jwt_header.is_empty(), origin) {
+		response.insert_header((jwt_header, jwt_assertion(&token.user, &origin, jwt_keypair).await?));
+	}
+
+	if let Some(cookie) = cookie {
+		Ok(response.cookie(cookie).finish())
+	} else {
+		Ok(response.finish())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rule_matches() {
+		let rule = AuthUrlRule {
+			path: "/public/**".to_string(),
+			methods: vec!["GET".to_string()],
+			public: true,
+			realms: vec![],
+		};
+
+		assert!(rule.matches("/public", "GET"));
+		assert!(rule.matches("/public/a/b", "get"));
+		assert!(!rule.matches("/publicity", "GET"));
+		assert!(!rule.matches("/public/a", "POST"));
+
+		assert_eq!(normalize_path("/public/../admin?a=1").unwrap(), "/admin");
+		assert_eq!(normalize_path("/public/%2E%2E/admin").unwrap(), "/admin");
+		assert_eq!(normalize_path("//a/./b/").unwrap(), "/a/b");
+		assert_eq!(normalize_path("/a;b/c").unwrap(), "/a;b/c");
+		assert_eq!(normalize_path("/public/..;/admin"), None);
+		assert_eq!(normalize_path("/public/%2e%2e;x=1/admin"), None);
+		assert_eq!(normalize_path("/public/.;/admin"), None);
+		assert_eq!(normalize_path("/public/..\\admin"), None);
+		assert_eq!(normalize_path("/public/%5C..%5Cadmin"), None);
+	}
+
+	#[actix_web::test]
+	async fn test_jwt_assertion() {
+		let user = crate::utils::tests::get_valid_user().await;
+		let keypair = RS256KeyPair::generate(2048).unwrap().with_key_id("default");
+
+		let jwt = jwt_assertion(&user, "http://localhost:8080", &keypair).await.unwrap();
+		let options = VerificationOptions {
+			allowed_audiences: Some(HashSet::from_strings(&["http://localhost:8080"])),
+			..Default::default()
+		};
+		let claims = keypair.public_key().verify_token::<BTreeMap<String, serde_json::Value>>(&jwt, Some(options)).unwrap();
+		assert_eq!(claims.subject.unwrap(), "valid@example.com");
+		assert_eq!(claims.issuer.unwrap(), "http://localhost:8080");
+		assert_eq!(claims.custom["realms"], serde_json::json!(["example"]));
+		assert_eq!(claims.custom["typ"], "AuthUrl");
+
+		let options = VerificationOptions {
+			allowed_audiences: Some(HashSet::from_strings(&["http://other.example.com"])),
+			..Default::default()
+		};
+		assert!(keypair.public_key().verify_token::<NoCustomClaims>(&jwt, Some(options)).is_err());
+	}
+
+	#[actix_web::test]
+	async fn test_user_headers() {
+		let user = crate::utils::tests::get_valid_user().await;
+
+		let headers = user_headers(&user, Some("http://grafana.localhost:3000")).await.unwrap();
+		assert_eq!(headers, vec![
+			("X-WEBAUTH-NAME".to_string(), "Valid User <valid@example.com>".to_string()),
+			("X-WEBAUTH-USER".to_string(), "valid".to_string()),
+		]);
+
+		let headers = user_headers(&user, Some("http://localhost:8080")).await.unwrap();
+		assert!(headers.contains(&("X-Auth-Realms".to_string(), "example".to_string())));
+		assert_eq!(headers, user_headers(&user, None).await.unwrap());
+	}
 }
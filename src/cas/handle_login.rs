@@ -0,0 +1,151 @@
+use actix_session::Session;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::cas::{issue_ticket, CASService};
+use crate::error::Response;
+use crate::token::SessionToken;
+use crate::{CAS_SERVICE_COOKIE, CONFIG};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CASLoginRequest {
+	pub service: Option<String>,
+	/// Don't ask the user to log in, just send them back without a ticket
+	#[serde(default)]
+	pub gateway: bool,
+}
+
+/// Credential requestor (CAS Protocol section 2.1)
+#[get("/cas/login")]
+pub async fn login(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, cas_req: web::Query<CASLoginRequest>) -> Response {
+	let user_session = SessionToken::from_session(&db, &session).await.ok();
+
+	let Some(service) = &cas_req.service else {
+		let location = if user_session.is_some() { "/" } else { "/login" };
+		return Ok(HttpResponse::Found()
+			.append_header(("Location", location))
+			.finish())
+	};
+
+	// Check before storing or redirecting to it, so that it's not an open redirect
+	CASService::from_service_url(&*CONFIG.read().await, service)?;
+
+	let location = if let Some(user_session) = user_session {
+		info!("Issuing CAS service ticket for {}", service);
+		issue_ticket(&db, &user_session, service).await?
+	} else if cas_req.gateway {
+		service.clone()
+	} else {
+		// Picked up by `get_post_login_location` after the login
+		session.insert(CAS_SERVICE_COOKIE, service.clone())?;
+		let base_url = CONFIG.read().await.url_from_request(&req);
+		format!("{}/login", base_url)
+	};
+
+	Ok(HttpResponse::Found()
+		.append_header(("Location", location))
+		.finish())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::cas::handle_validate::{service_validate, service_validate_p3};
+	use crate::token::MagicLinkToken;
+	use crate::utils::tests::*;
+
+	use actix_session::storage::CookieSessionStore;
+	use actix_session::SessionMiddleware;
+	use actix_web::cookie::{Cookie, Key};
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_cas_login() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let secret = Key::from(&[0; 64]);
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.service(crate::handle_login_link::login_link)
+				.service(login)
+				.service(service_validate)
+				.service(service_validate_p3)
+				.wrap(
+					SessionMiddleware::builder(
+						CookieSessionStore::default(),
+						secret
+					)
+					.build())
+		)
+		.await;
+
+		let service = "https://legacy.example.com/cas?next=%2Fpage";
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/cas/login?service={}", urlencoding::encode(service)).as_str())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert!(resp.headers().get("Location").unwrap().to_str().unwrap().ends_with("/login"));
+		let cookie = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
+
+		// After the login the user is sent back to the service with a ticket
+		let token = MagicLinkToken::new(db, user, None, None).await.unwrap();
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/login/{}", token.code).as_str())
+			.cookie(cookie)
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		let location = resp.headers().get("Location").unwrap().to_str().unwrap().to_string();
+		let ticket = location.strip_prefix(&format!("{}&ticket=", service)).unwrap().to_string();
+		assert!(ticket.starts_with("ST-"));
+		let cookie: Cookie<'_> = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
+
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/cas/p3/serviceValidate?service={}&ticket={}", urlencoding::encode(service), ticket).as_str())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body = actix_test::read_body(resp).await;
+		let body_str = std::str::from_utf8(&body).unwrap();
+		assert!(body_str.contains("<cas:user>valid</cas:user>"));
+		assert!(body_str.contains("<cas:email>valid@example.com</cas:email>"));
+		assert!(body_str.contains("<cas:realms>example</cas:realms>"));
+
+		// Tickets can only be used once
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/cas/serviceValidate?service={}&ticket={}", urlencoding::encode(service), ticket).as_str())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		let body = actix_test::read_body(resp).await;
+		assert!(std::str::from_utf8(&body).unwrap().contains(r#"code="INVALID_TICKET""#));
+
+		// Logged in users get a ticket right away, that is only good for the service it was issued for
+		let req = actix_test::TestRequest::get()
+			.uri("/cas/login?service=https%3A%2F%2Flegacy.example.com%2F")
+			.cookie(cookie.clone())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+		let ticket = location.strip_prefix("https://legacy.example.com/?ticket=").unwrap();
+
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/cas/serviceValidate?service=https%3A%2F%2Flegacy.example.com%2Fother&ticket={}", ticket).as_str())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		let body = actix_test::read_body(resp).await;
+		assert!(std::str::from_utf8(&body).unwrap().contains(r#"code="INVALID_SERVICE""#));
+
+		// Services that aren't allowlisted are rejected
+		let req = actix_test::TestRequest::get()
+			.uri("/cas/login?service=https%3A%2F%2Fevil.example.com%2F")
+			.cookie(cookie)
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+	}
+}
@@ -0,0 +1,73 @@
+use actix_session::Session;
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::cas::CASService;
+use crate::error::Response;
+use crate::oidc::handle_check_session::get_browser_state_cookie;
+use crate::token::SessionToken;
+use crate::{CONFIG, SESSION_COOKIE};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CASLogoutRequest {
+	pub service: Option<String>,
+}
+
+/// Logout (CAS Protocol section 2.3), only redirects back to allowlisted services
+#[get("/cas/logout")]
+pub async fn logout(session: Session, db: web::Data<reindeer::Db>, cas_req: web::Query<CASLogoutRequest>) -> Response {
+	if let Some(Ok(user_session_id)) = session.remove_as::<String>(SESSION_COOKIE) {
+		let token = SessionToken::from_code(&db, &user_session_id).await?;
+		token.delete(&db).await?;
+	}
+
+	let config = CONFIG.read().await;
+	let target_url = match &cas_req.service {
+		Some(service) if CASService::from_service_url(&config, service).is_ok() => service.clone(),
+		_ => "/login".to_string(),
+	};
+
+	Ok(HttpResponse::Found()
+		.append_header(("Location", target_url.as_str()))
+		.cookie(get_browser_state_cookie(String::new()))
+		.finish())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_cas_logout() {
+		let db = &db_connect().await;
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.service(logout)
+				.wrap(actix_session::SessionMiddleware::new(
+					actix_session::storage::CookieSessionStore::default(),
+					actix_web::cookie::Key::from(&[0; 64])
+				))
+		)
+		.await;
+
+		let req = actix_test::TestRequest::get()
+			.uri("/cas/logout?service=https%3A%2F%2Flegacy.example.com%2F")
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert_eq!(resp.headers().get("Location").unwrap(), "https://legacy.example.com/");
+
+		// Unknown services are not redirected to
+		let req = actix_test::TestRequest::get()
+			.uri("/cas/logout?service=https%3A%2F%2Fevil.example.com%2F")
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert_eq!(resp.headers().get("Location").unwrap(), "/login");
+	}
+}
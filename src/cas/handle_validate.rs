@@ -0,0 +1,104 @@
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse};
+use log::{info, warn};
+use quick_xml::escape::escape;
+use serde::{Deserialize, Serialize};
+
+use crate::cas::{CASService, CAS_NS, CAS_TICKET_PREFIX};
+use crate::error::{AppErrorKind, Result};
+use crate::token::CASServiceTicketToken;
+use crate::user::User;
+use crate::CONFIG;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CASValidateRequest {
+	pub service: Option<String>,
+	pub ticket: Option<String>,
+}
+
+/// Consumes the ticket and returns the user it was issued for
+async fn validate(db: &reindeer::Db, cas_req: &CASValidateRequest) -> Result<User> {
+	let (Some(service), Some(ticket)) = (&cas_req.service, &cas_req.ticket) else {
+		return Err(AppErrorKind::MissingCASParameter.into());
+	};
+
+	let code = ticket.strip_prefix(CAS_TICKET_PREFIX).ok_or(AppErrorKind::InvalidCASTicket)?;
+	// The ticket kind is ephemeral, so it can't be validated twice
+	let token = CASServiceTicketToken::from_code(db, &code.to_string())
+		.await
+		.map_err(|_| AppErrorKind::InvalidCASTicket)?;
+
+	if token.metadata.as_ref() != Some(service) {
+		warn!("CAS ticket was issued for {:?} but validated by {}", token.metadata, service);
+		return Err(AppErrorKind::InvalidCASService.into());
+	}
+
+	// The service or the user's realms might have changed since the ticket was issued
+	let config = CONFIG.read().await;
+	let cas_service = CASService::from_service_url(&config, service)?;
+	if !token.user.has_any_realm(&cas_service.realms) {
+		return Err(AppErrorKind::InvalidCASService.into());
+	}
+
+	Ok(token.user)
+}
+
+fn attribute(name: &str, value: &str) -> String {
+	format!("<cas:{name}>{}</cas:{name}>", escape(value), name = name)
+}
+
+/// Builds the `cas:serviceResponse`, the failures are reported with a 200 as well (CAS Protocol section 2.5.3)
+fn service_response(result: Result<User>, with_attributes: bool) -> HttpResponse {
+	let body = match result {
+		Ok(user) => {
+			let attributes = if with_attributes {
+				let realms = user.realms.iter().map(|r| attribute("realms", r)).collect::<String>();
+				format!("<cas:attributes>{}{}{}{}</cas:attributes>",
+					attribute("email", &user.email),
+					attribute("name", &user.name),
+					attribute("username", &user.username),
+					realms)
+			} else {
+				String::new()
+			};
+
+			info!("Validated CAS service ticket for {}", &user.email);
+			format!("<cas:authenticationSuccess>{}{}</cas:authenticationSuccess>", attribute("user", &user.username), attributes)
+		},
+		Err(e) => {
+			let failure = [
+				(AppErrorKind::MissingCASParameter, "INVALID_REQUEST"),
+				(AppErrorKind::InvalidCASTicket, "INVALID_TICKET"),
+				(AppErrorKind::InvalidCASService, "INVALID_SERVICE"),
+			]
+				.into_iter()
+				.find(|(kind, _)| e.is(kind.clone()));
+
+			let (code, description) = match failure {
+				Some((kind, code)) => (code, kind.to_string()),
+				None => {
+					log::error!("CAS ticket validation failed: {}", e);
+					("INTERNAL_ERROR", "Something went very wrong from our end".to_string())
+				},
+			};
+
+			format!(r#"<cas:authenticationFailure code="{}">{}</cas:authenticationFailure>"#, code, escape(description))
+		},
+	};
+
+	HttpResponse::Ok()
+		.content_type(ContentType::xml())
+		.body(format!(r#"<cas:serviceResponse xmlns:cas="{}">{}</cas:serviceResponse>"#, CAS_NS, body))
+}
+
+/// Service ticket validation (CAS Protocol section 2.5)
+#[get("/cas/serviceValidate")]
+pub async fn service_validate(db: web::Data<reindeer::Db>, cas_req: web::Query<CASValidateRequest>) -> HttpResponse {
+	service_response(validate(&db, &cas_req).await, false)
+}
+
+/// Service ticket validation with the user attributes (CAS Protocol section 2.5 of version 3.0)
+#[get("/cas/p3/serviceValidate")]
+pub async fn service_validate_p3(db: web::Data<reindeer::Db>, cas_req: web::Query<CASValidateRequest>) -> HttpResponse {
+	service_response(validate(&db, &cas_req).await, true)
+}
@@ -0,0 +1,60 @@
+use actix_web::http::Uri;
+use reindeer::Db;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigFile;
+use crate::error::{AppErrorKind, Result};
+use crate::token::{CASServiceTicketToken, SessionToken};
+use crate::CONFIG;
+
+pub mod handle_login;
+pub mod handle_logout;
+pub mod handle_validate;
+
+/// Service tickets have to start with `ST-` (CAS Protocol section 3.1.1)
+pub const CAS_TICKET_PREFIX: &str = "ST-";
+pub const CAS_NS: &str = "http://www.yale.edu/tp/cas";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CASService {
+	pub origin: String,
+	pub realms: Vec<String>,
+}
+
+impl CASService {
+	/// Finds the allowlisted service that the service URL belongs to
+	pub fn from_service_url<'a>(config: &'a ConfigFile, service: &str) -> Result<&'a Self> {
+		let service_uri = service.parse::<Uri>().map_err(|_| AppErrorKind::InvalidCASService)?;
+		let scheme = service_uri.scheme_str().ok_or(AppErrorKind::InvalidCASService)?;
+		let authority = service_uri.authority().ok_or(AppErrorKind::InvalidCASService)?;
+		let origin = format!("{}://{}", scheme, authority);
+
+		config.cas_services
+			.iter()
+			.find(|s| s.origin == origin)
+			.ok_or(AppErrorKind::InvalidCASService.into())
+	}
+}
+
+/// Issues a service ticket for the session and returns the service URL that carries it
+pub async fn issue_ticket(db: &Db, user_session: &SessionToken, service: &str) -> Result<String> {
+	let config = CONFIG.read().await;
+	let cas_service = CASService::from_service_url(&config, service)?;
+	if !user_session.user.has_any_realm(&cas_service.realms) {
+		return Err(AppErrorKind::InvalidCASService.into());
+	}
+	// Tickets are short lived, they shouldn't last as long as the session
+	let duration = config.oidc_code_duration;
+	drop(config);
+
+	let ticket = CASServiceTicketToken::new_with_duration(
+		db,
+		user_session.user.clone(),
+		Some(user_session.code.clone()),
+		Some(service.to_string()),
+		Some(duration),
+	).await?;
+
+	let separator = if service.contains('?') { '&' } else { '?' };
+	Ok(format!("{}{}ticket={}{}", service, separator, CAS_TICKET_PREFIX, ticket.code))
+}
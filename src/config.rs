@@ -29,12 +29,37 @@ pub struct ConfigFile {
 	pub auth_url_name_header: String,
 	pub auth_url_email_header: String,
 	pub auth_url_realms_header: String,
+	/// The header with a JWT signed by the OIDC key asserting the user, empty (the default) to disable
+	pub auth_url_jwt_header: String,
+	#[serde(deserialize_with = "duration_str::deserialize_duration_chrono")]
+	pub auth_url_jwt_duration: Duration,
 	pub auth_url_scopes: Vec<crate::auth_url::AuthUrlScope>,
 
 	pub oidc_enable: bool,
 	#[serde(deserialize_with = "duration_str::deserialize_duration_chrono")]
 	pub oidc_code_duration: Duration,
 	pub oidc_clients: Vec<crate::oidc::client::OIDCClient>,
+	pub oidc_webfinger_unknown_users: bool,
+
+	pub cas_enable: bool,
+	pub cas_services: Vec<crate::cas::CASService>,
+
+	pub ldap_enable: bool,
+	pub ldap_listen_port: u16,
+	pub ldap_base_dn: String,
+	pub ldap_services: Vec<crate::ldap::LDAPService>,
+
+	pub registry_enable: bool,
+	#[serde(deserialize_with = "duration_str::deserialize_duration_chrono")]
+	pub registry_token_duration: Duration,
+	pub registry_services: Vec<crate::registry::RegistryService>,
+
+	pub ssh_ca_enable: bool,
+	#[serde(deserialize_with = "duration_str::deserialize_duration_chrono")]
+	pub ssh_ca_certificate_duration: Duration,
+
+	pub saml_enable: bool,
+	pub saml_service_providers: Vec<crate::saml::service_provider::SAMLServiceProvider>,
 
 	pub smtp_enable: bool,
 	pub smtp_url: String,
@@ -74,11 +99,32 @@ impl Default for ConfigFile {
 			auth_url_email_header: "X-Auth-Email".to_string(),
 			auth_url_name_header : "X-Auth-Name".to_string(),
 			auth_url_realms_header: "X-Auth-Realms".to_string(),
+			auth_url_jwt_header  : String::new(),
+			auth_url_jwt_duration: Duration::try_minutes(1).unwrap(),
 			auth_url_scopes      : vec![],
 
 			oidc_enable       : true,
 			oidc_code_duration: Duration::try_minutes(1).unwrap(),
 			oidc_clients      : vec![],
+			oidc_webfinger_unknown_users: false,
+
+			cas_enable  : false,
+			cas_services: vec![],
+
+			ldap_enable     : false,
+			ldap_listen_port: 3890,
+			ldap_base_dn    : "dc=example,dc=com".to_string(),
+			ldap_services   : vec![],
+
+			registry_enable        : false,
+			registry_token_duration: Duration::try_minutes(5).unwrap(),
+			registry_services      : vec![],
+
+			ssh_ca_enable              : false,
+			ssh_ca_certificate_duration: Duration::try_hours(1).unwrap(),
+
+			saml_enable           : false,
+			saml_service_providers: vec![],
 
 			smtp_enable : false,
 			smtp_url    : "smtp://localhost:25".to_string(),
@@ -149,6 +195,9 @@ impl ConfigFile {
 pub enum ConfigKeys {
 	Secret,
 	JWTKeyPair,
+	SAMLKey,
+	SAMLCertificate,
+	SshCaKey,
 }
 
 impl AsBytes for ConfigKeys {
@@ -89,6 +89,8 @@ impl IngressConfig {
 				config.auth_url_scopes.push(AuthUrlScope {
 					realms: self.realms.clone(),
 					origin,
+					rules: vec![],
+					headers: Default::default(),
 				});
 			}
 		}
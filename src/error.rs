@@ -55,6 +55,8 @@ pub enum AppErrorKind {
 	NoClientSecretOrCodeChallenge,
 	#[display(fmt = "Client sent a code_challenge_method that is not S256")]
 	InvalidCodeChallengeMethod,
+	#[display(fmt = "Client must use PKCE for this redirect_uri")]
+	PKCERequired,
 	#[display(fmt = "Client sent a code_verifier but did not send a code_challenge")]
 	NoCodeChallenge,
 	#[display(fmt = "Someone tried to get a token with an invalid invalid OIDC code")]
@@ -63,6 +65,74 @@ pub enum AppErrorKind {
 	InvalidCodeVerifier,
 	#[display(fmt = "The client tried to create a token without providing any credentials (client_verifier or client_secret)")]
 	NoClientCredentialsProvided,
+	#[display(fmt = "The client requested an unsupported grant_type")]
+	UnsupportedGrantType,
+	#[display(fmt = "Client did not send a subject_token")]
+	NoSubjectToken,
+	#[display(fmt = "Client sent an unsupported subject_token_type or requested_token_type")]
+	UnsupportedTokenType,
+	#[display(fmt = "Client sent a subject_token that is invalid or expired")]
+	InvalidSubjectToken,
+	#[display(fmt = "Client did not send the audience of the token exchange")]
+	NoAudience,
+	#[display(fmt = "Client is not allowed to exchange tokens for the requested audience")]
+	TokenExchangeNotAllowed,
+	#[display(fmt = "Client requested a resource that is not allowed for it")]
+	InvalidTarget,
+	#[display(fmt = "The token is not intended for this resource")]
+	InvalidTokenAudience,
+	#[display(fmt = "Client tried to use an OIDC code that has already been used")]
+	OIDCCodeReplayed,
+	#[display(fmt = "Client requested a scope that is unknown or not allowed for it")]
+	InvalidScope,
+	#[display(fmt = "Client did not send a refresh_token")]
+	NoRefreshToken,
+	#[display(fmt = "Client sent an invalid claims request parameter")]
+	InvalidClaimsRequest,
+	#[display(fmt = "The logged in user is not the one requested with the sub claim")]
+	SubjectMismatch,
+	#[display(fmt = "Client is not allowed to use backchannel authentication")]
+	CIBANotAllowed,
+	#[display(fmt = "Client did not send a login_hint")]
+	MissingLoginHint,
+	#[display(fmt = "Client sent a login_hint of an unknown user")]
+	UnknownUserID,
+	#[display(fmt = "Client in ping mode did not send a client_notification_token")]
+	MissingClientNotificationToken,
+	#[display(fmt = "Client did not send an auth_req_id")]
+	MissingAuthReqID,
+	#[display(fmt = "The user has not approved the backchannel authentication yet")]
+	CIBAAuthorizationPending,
+	#[display(fmt = "Client is polling too fast")]
+	CIBASlowDown,
+	#[display(fmt = "The backchannel authentication request has expired")]
+	CIBARequestExpired,
+	#[display(fmt = "The user denied the backchannel authentication")]
+	CIBAAccessDenied,
+	#[display(fmt = "Service provider sent an invalid SAML request")]
+	InvalidSAMLRequest,
+	#[display(fmt = "Service provider sent an entity ID that is not in the config")]
+	InvalidSAMLServiceProvider,
+	#[display(fmt = "Service provider sent an Assertion Consumer Service URL different from the ones in the config")]
+	InvalidSAMLAssertionConsumerService,
+	#[display(fmt = "Service did not send both the service and the ticket")]
+	MissingCASParameter,
+	#[display(fmt = "Service sent a ticket that is invalid, expired or already used")]
+	InvalidCASTicket,
+	#[display(fmt = "Service is not allowed to use CAS or the ticket was issued for another service")]
+	InvalidCASService,
+	#[display(fmt = "Registry asked for a token for a service that is not in the config")]
+	InvalidRegistryService,
+	#[display(fmt = "User sent an invalid SSH public key")]
+	InvalidSSHPublicKey,
+	#[display(fmt = "Client did not send the resource of the WebFinger request")]
+	MissingWebFingerResource,
+	#[display(fmt = "Client sent an invalid DPoP proof")]
+	InvalidDPoPProof,
+	#[display(fmt = "Client sent a DPoP proof that has already been used")]
+	DPoPProofReplayed,
+	#[display(fmt = "Client is required to send a DPoP proof")]
+	DPoPRequired,
 PasskeyAlreadyRegistered,
 }
 
@@ -73,6 +143,12 @@ pub struct Error {
 	app_error: Option<AppErrorKind>,
 }
 
+impl Error {
+	pub fn is(&self, kind: AppErrorKind) -> bool {
+		self.app_error == Some(kind)
+	}
+}
+
 impl ResponseError for Error {
 	fn status_code(&self) -> StatusCode {
 		if let Some(app_error) = &self.app_error {
@@ -80,6 +156,8 @@ impl ResponseError for Error {
 				AppErrorKind::TokenNotFound => StatusCode::FOUND,
 				AppErrorKind::NotLoggedIn |
 				AppErrorKind::InvalidOIDCCode |
+				AppErrorKind::InvalidTokenAudience |
+				AppErrorKind::DPoPRequired |
 				AppErrorKind::InvalidClientID |
 				AppErrorKind::InvalidClientSecret => StatusCode::UNAUTHORIZED,
 				AppErrorKind::NotFound => StatusCode::NOT_FOUND,
@@ -267,6 +345,18 @@ impl From<jwt_simple::reexports::ct_codecs::Error> for Error {
 	}
 }
 
+impl From<openssl::error::ErrorStack> for Error {
+	fn from(error: openssl::error::ErrorStack) -> Self {
+		format!("OpenSSL error: {}", error).into()
+	}
+}
+
+impl From<ssh_key::Error> for Error {
+	fn from(error: ssh_key::Error) -> Self {
+		format!("SSH key error: {}", error).into()
+	}
+}
+
 impl From<webauthn_rs::prelude::WebauthnError> for Error {
 	fn from(error: webauthn_rs::prelude::WebauthnError) -> Self {
 		format!("WebAuthN error: {}", error).into()
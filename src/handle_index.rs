@@ -2,19 +2,23 @@ use std::collections::BTreeMap;
 
 use actix_session::Session;
 use actix_web::http::header::{self, ContentType};
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 
+use crate::auth_url::user_headers;
 use crate::error::Response;
 use crate::token::{ProxyCookieToken, SessionToken};
-use crate::utils::get_partial;
+use crate::utils::{get_partial, get_request_origin};
 use crate::{CONFIG, SCOPED_LOGIN};
 
 #[get("/")]
-async fn index(session: Session, db: web::Data<reindeer::Db>) -> Response {
+async fn index(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>) -> Response {
 	let token = SessionToken::from_session(&db, &session).await?;
 
 	let mut index_data = BTreeMap::new();
 	index_data.insert("email", token.user.email.clone());
+	if CONFIG.read().await.ssh_ca_enable {
+		index_data.insert("ssh_ca_enable", "true".to_string());
+	}
 	let index_page = get_partial("index", index_data)?;
 
 
@@ -24,13 +28,13 @@ async fn index(session: Session, db: web::Data<reindeer::Db>) -> Response {
 			.append_header((header::LOCATION, format!("{}?code={}", scope, proxy_cookie.code)))
 			.finish())
 	} else {
-		let config = CONFIG.read().await;
-		Ok(HttpResponse::Ok()
-			// TODO: Add realm
-			.append_header((config.auth_url_email_header.as_str(), token.user.email.clone()))
-			.append_header((config.auth_url_user_header.as_str(), token.user.username.clone()))
-			.append_header((config.auth_url_name_header.as_str(), token.user.name.clone()))
-			.append_header((config.auth_url_realms_header.as_str(), token.user.realms.join(",")))
+		let origin = get_request_origin(&req).ok();
+		let mut response = HttpResponse::Ok();
+		for header in user_headers(&token.user, origin.as_deref()).await? {
+			response.append_header(header);
+		}
+
+		Ok(response
 			.content_type(ContentType::html())
 			.body(index_page))
 	}
@@ -47,7 +51,7 @@ mod tests {
 
 	use actix_session::storage::CookieSessionStore;
 	use actix_session::SessionMiddleware;
-	use actix_web::cookie::{Cookie, Key, SameSite};
+	use actix_web::cookie::{Key, SameSite};
 	use actix_web::http::StatusCode;
 	use actix_web::{test as actix_test, App};
 
@@ -89,9 +93,7 @@ mod tests {
 		assert_eq!(resp.status(), StatusCode::FOUND);
 		assert_eq!(resp.headers().get("Location").unwrap(), "/");
 
-		let headers = resp.headers().clone();
-		let cookie_header = headers.get("set-cookie").unwrap().to_str().unwrap();
-		let parsed_cookie = Cookie::parse_encoded(cookie_header).unwrap();
+		let parsed_cookie = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
 
 		let req = actix_test::TestRequest::get()
 			.uri("/")
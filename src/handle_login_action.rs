@@ -11,7 +11,7 @@ use log::info;
 use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
 
-use crate::error::Response;
+use crate::error::{Response, Result};
 use crate::token::MagicLinkToken;
 use crate::user::User;
 use crate::utils::get_partial;
@@ -67,27 +67,25 @@ impl From<ScopedLogin> for String {
 	}
 }
 
-#[post("/login")]
-async fn login_action(req: HttpRequest, session: Session, form: web::Form<LoginInfo>, db: web::Data<reindeer::Db>, mailer: web::Data<Option<SmtpTransport>>, http_client: web::Data<Option<reqwest::Client>>) -> Response {
-	let login_action_page = get_partial("login_action", BTreeMap::new())?;
-	let result = Ok(HttpResponse::Ok()
-		.content_type(ContentType::html())
-		.body(login_action_page));
-	let Some(user) = User::from_config(&form.email).await else {
-		// Return 200 to avoid leaking valid emails
-		return result;
-	};
-
-	let link = MagicLinkToken::new(&db, user.clone(), None, None).await?;
-	let config = CONFIG.read().await;
-	let base_url = config.url_from_request(&req);
+/// Creates a magic link for the user and sends it over the configured email and/or HTTP request
+pub async fn send_magic_link(req: &HttpRequest, db: &reindeer::Db, mailer: &Option<SmtpTransport>, http_client: &Option<reqwest::Client>, user: &User) -> Result<()> {
+	let link = MagicLinkToken::new(db, user.clone(), None, None).await?;
+	let base_url = CONFIG.read().await.url_from_request(req);
 	let magic_link = format!("{}/login/{}", base_url, link.code);
-	let name = &user.name.clone();
-	let username = &user.username.clone();
 
 	#[cfg(debug_assertions)]
 	println!("Link: {} {:?}", &magic_link, link);
 
+	send_link(mailer, http_client, user, &magic_link).await
+}
+
+/// Sends a link to the user over the configured email and/or HTTP request,
+/// filling it in as the `magic_link` of the templates
+pub async fn send_link(mailer: &Option<SmtpTransport>, http_client: &Option<reqwest::Client>, user: &User, magic_link: &str) -> Result<()> {
+	let config = CONFIG.read().await;
+	let name = &user.name.clone();
+	let username = &user.username.clone();
+
 	if let Some(mailer) = mailer.as_ref() {
 		let email = Message::builder()
 			.from(config.smtp_from.parse()?)
@@ -97,7 +95,7 @@ async fn login_action(req: HttpRequest, session: Session, form: web::Form<LoginI
 			.body(formatx!(
 				&config.smtp_body,
 				title = &config.title,
-				magic_link = &magic_link,
+				magic_link = magic_link,
 				name = name,
 				username = username
 			)?)?;
@@ -111,7 +109,7 @@ async fn login_action(req: HttpRequest, session: Session, form: web::Form<LoginI
 		let url = formatx!(
 			&config.request_url,
 			title = &config.title,
-			magic_link = &magic_link,
+			magic_link = magic_link,
 			email = &user.email,
 			name = name,
 			username = username
@@ -122,7 +120,7 @@ async fn login_action(req: HttpRequest, session: Session, form: web::Form<LoginI
 			let body = formatx!(
 				data.as_str(),
 				title = &config.title,
-				magic_link = &magic_link,
+				magic_link = magic_link,
 				email = &user.email,
 				name = name,
 				username = username
@@ -140,12 +138,32 @@ async fn login_action(req: HttpRequest, session: Session, form: web::Form<LoginI
 		}
 	}
 
+	Ok(())
+}
+
+/// The page telling the user to check their inbox
+pub fn login_action_page() -> Response {
+	let login_action_page = get_partial("login_action", BTreeMap::new())?;
+	Ok(HttpResponse::Ok()
+		.content_type(ContentType::html())
+		.body(login_action_page))
+}
+
+#[post("/login")]
+async fn login_action(req: HttpRequest, session: Session, form: web::Form<LoginInfo>, db: web::Data<reindeer::Db>, mailer: web::Data<Option<SmtpTransport>>, http_client: web::Data<Option<reqwest::Client>>) -> Response {
+	let Some(user) = User::from_config(&form.email).await else {
+		// Return 200 to avoid leaking valid emails
+		return login_action_page();
+	};
+
+	send_magic_link(&req, &db, &mailer, &http_client, &user).await?;
+
 	if let Ok(scoped) = serde_qs::from_str::<ScopedLogin>(req.query_string()) {
 		println!("Setting scoped login for link: {:?}", &scoped);
 		session.insert(SCOPED_LOGIN, scoped)?;
 	}
 
-	result
+	login_action_page()
 }
 
 #[cfg(test)]
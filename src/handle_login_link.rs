@@ -4,6 +4,7 @@ use actix_web::{get, web, HttpResponse};
 use log::info;
 
 use crate::error::Response;
+use crate::oidc::handle_check_session::{get_browser_state, get_browser_state_cookie};
 use crate::token::{MagicLinkToken, SessionToken};
 use crate::utils::get_post_login_location;
 use crate::SESSION_COOKIE;
@@ -19,6 +20,7 @@ async fn login_link(magic: web::Path<String>, session: Session, db: web::Data<re
 
 	Ok(HttpResponse::Found()
 		.append_header((header::LOCATION, redirect_url))
+		.cookie(get_browser_state_cookie(get_browser_state(&user_session.code)))
 		.finish())
 }
 
@@ -4,12 +4,19 @@ use actix_session::Session;
 use actix_web::http::header::ContentType;
 use actix_web::{get, web, HttpRequest, HttpResponse};
 use log::info;
+use serde::{Deserialize, Serialize};
 
 use crate::error::{AppErrorKind, Response};
 use crate::handle_login_action::ScopedLogin;
 use crate::token::{ProxyCookieToken, SessionToken};
 use crate::utils::get_partial;
 
+/// The `login_hint` of an OIDC authorize request, carried over to the login page
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LoginHint {
+	pub login_hint: Option<String>,
+}
+
 #[get("/login")]
 async fn login_page(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>) -> Response {
 	if let Ok(user_session) = SessionToken::from_session(&db, &session).await {
@@ -27,7 +34,11 @@ async fn login_page(req: HttpRequest, session: Session, db: web::Data<reindeer::
 			.finish())
 	}
 
-	let login_page = get_partial("login", BTreeMap::new())?;
+	let mut login_data = BTreeMap::new();
+	if let Ok(LoginHint { login_hint: Some(login_hint) }) = serde_qs::from_str::<LoginHint>(req.query_string()) {
+		login_data.insert("email", login_hint);
+	}
+	let login_page = get_partial("login", login_data)?;
 
 	Ok(HttpResponse::Ok()
 		.content_type(ContentType::html())
@@ -71,5 +82,12 @@ mod tests {
 		let resp = actix_test::call_service(&mut app, req).await;
 		assert_eq!(resp.status(), StatusCode::OK);
 		assert_eq!(resp.headers().get("Content-Type").unwrap().to_str().unwrap(), ContentType::html().to_string().as_str());
+
+		let req = actix_test::TestRequest::get()
+			.uri("/login?client_id=my_client&login_hint=valid%40example.com")
+			.to_request();
+
+		let body = actix_test::call_and_read_body(&mut app, req).await;
+		assert!(std::str::from_utf8(&body).unwrap().contains("value=\"valid@example.com\""));
 	}
 }
@@ -6,6 +6,7 @@ use log::warn;
 use serde::{Deserialize, Serialize};
 
 use crate::error::Response;
+use crate::oidc::handle_check_session::get_browser_state_cookie;
 use crate::token::SessionToken;
 use crate::SESSION_COOKIE;
 
@@ -33,5 +34,7 @@ async fn logout(req: web::Query<LogoutRequest>, session: Session, db: web::Data<
 
 	Ok(HttpResponse::Found()
 		.append_header(("Location", target_url.as_str()))
+		// Let the check session iframes know that the session is gone
+		.cookie(get_browser_state_cookie(String::new()))
 		.finish())
 }
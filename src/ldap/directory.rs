@@ -0,0 +1,303 @@
+use std::collections::BTreeSet;
+
+use ldap3_proto::proto::{LdapFilter, LdapPartialAttribute, LdapSearchResultEntry, LdapSearchScope, LdapSubstringFilter};
+
+use crate::config::ConfigFile;
+use crate::ldap::LDAPService;
+use crate::user::User;
+
+/// An entry of the directory, with the attribute names as they're returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+	pub dn: String,
+	pub attributes: Vec<(String, Vec<String>)>,
+}
+
+impl Entry {
+	fn new(dn: String, attributes: &[(&str, Vec<String>)]) -> Self {
+		Self {
+			dn,
+			attributes: attributes.iter().map(|(name, values)| (name.to_string(), values.clone())).collect(),
+		}
+	}
+
+	pub fn get(&self, name: &str) -> Option<&Vec<String>> {
+		self.attributes
+			.iter()
+			.find(|(n, _)| n.eq_ignore_ascii_case(name))
+			.map(|(_, values)| values)
+	}
+
+	/// `1.1` asks for no attributes, `*` or nothing for all of them (RFC 4511 section 4.5.1.8)
+	pub fn to_result_entry(&self, attrs: &[String]) -> LdapSearchResultEntry {
+		let all = attrs.is_empty() || attrs.iter().any(|a| a == "*");
+
+		LdapSearchResultEntry {
+			dn: self.dn.clone(),
+			attributes: self.attributes
+				.iter()
+				.filter(|(name, _)| all || attrs.iter().any(|a| a.eq_ignore_ascii_case(name)))
+				.map(|(name, values)| LdapPartialAttribute {
+					atype: name.clone(),
+					vals: values.iter().map(|v| v.as_bytes().to_vec()).collect(),
+				})
+				.collect(),
+		}
+	}
+
+	pub fn matches(&self, filter: &LdapFilter) -> bool {
+		match filter {
+			LdapFilter::And(filters) => filters.iter().all(|f| self.matches(f)),
+			LdapFilter::Or(filters) => filters.iter().any(|f| self.matches(f)),
+			LdapFilter::Not(filter) => !self.matches(filter),
+			LdapFilter::Equality(name, value) |
+			LdapFilter::Approx(name, value) => self.get(name).is_some_and(|values| values.iter().any(|v| v.eq_ignore_ascii_case(value))),
+			LdapFilter::Substring(name, substring) => self.get(name).is_some_and(|values| values.iter().any(|v| matches_substring(v, substring))),
+			LdapFilter::Present(name) => self.get(name).is_some(),
+			// Nothing in the directory is ordered or needs a matching rule
+			_ => false,
+		}
+	}
+
+	pub fn in_scope(&self, base: &str, scope: &LdapSearchScope) -> bool {
+		let dn = normalize_rdns(&self.dn);
+		let base = normalize_rdns(base);
+
+		if !dn.ends_with(&base) {
+			return false;
+		}
+		let depth = dn.len() - base.len();
+
+		match scope {
+			LdapSearchScope::Base => depth == 0,
+			LdapSearchScope::OneLevel => depth == 1,
+			LdapSearchScope::Subtree => true,
+			LdapSearchScope::Children => depth > 0,
+		}
+	}
+}
+
+fn matches_substring(value: &str, substring: &LdapSubstringFilter) -> bool {
+	let mut rest = value.to_lowercase();
+
+	if let Some(initial) = &substring.initial {
+		let Some(stripped) = rest.strip_prefix(&initial.to_lowercase()) else {
+			return false;
+		};
+		rest = stripped.to_string();
+	}
+
+	for any in &substring.any {
+		let any = any.to_lowercase();
+		let Some(index) = rest.find(&any) else {
+			return false;
+		};
+		rest = rest[index + any.len()..].to_string();
+	}
+
+	substring.final_.as_ref().map_or(true, |f| rest.ends_with(&f.to_lowercase()))
+}
+
+/// Splits at the `separator`s that are not escaped with a backslash
+fn split_unescaped(value: &str, separator: char) -> Vec<&str> {
+	let mut parts = Vec::new();
+	let mut start = 0;
+	let mut escaped = false;
+
+	for (i, c) in value.char_indices() {
+		match c {
+			_ if escaped => escaped = false,
+			'\\' => escaped = true,
+			_ if c == separator => {
+				parts.push(&value[start..i]);
+				start = i + c.len_utf8();
+			},
+			_ => {},
+		}
+	}
+
+	parts.push(&value[start..]);
+	parts
+}
+
+/// The RDNs of a DN without case and without the spaces around the separators,
+/// keeping the escaped ones (RFC 4514 section 2.4)
+fn normalize_rdns(dn: &str) -> Vec<String> {
+	if dn.trim().is_empty() {
+		return vec![];
+	}
+
+	split_unescaped(dn, ',')
+		.iter()
+		.map(|rdn| split_unescaped(rdn, '=')
+			.iter()
+			.map(|part| {
+				let part = part.trim_start();
+				let trimmed = part.trim_end();
+				// A trailing space can only be part of the value when it's escaped
+				if trimmed.ends_with('\\') && trimmed.len() < part.len() {
+					&part[..=trimmed.len()]
+				} else {
+					trimmed
+				}
+			})
+			.collect::<Vec<_>>()
+			.join("=")
+			.to_lowercase())
+		.collect()
+}
+
+/// DNs are compared without case and without the spaces around the separators
+pub fn normalize_dn(dn: &str) -> String {
+	normalize_rdns(dn).join(",")
+}
+
+/// Escapes an attribute value to be used in a DN (RFC 4514 section 2.4)
+pub fn escape_dn_value(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	let last = value.chars().count().saturating_sub(1);
+
+	for (i, c) in value.chars().enumerate() {
+		match c {
+			'"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => {
+				escaped.push('\\');
+				escaped.push(c);
+			},
+			'#' | ' ' if i == 0 => {
+				escaped.push('\\');
+				escaped.push(c);
+			},
+			' ' if i == last => escaped.push_str("\\ "),
+			'\0' => escaped.push_str("\\00"),
+			_ => escaped.push(c),
+		}
+	}
+
+	escaped
+}
+
+pub fn service_dn(config: &ConfigFile, service: &LDAPService) -> String {
+	format!("cn={},ou=services,{}", escape_dn_value(&service.name), config.ldap_base_dn)
+}
+
+pub fn user_dn(config: &ConfigFile, username: &str) -> String {
+	format!("uid={},ou=users,{}", escape_dn_value(username), config.ldap_base_dn)
+}
+
+pub fn group_dn(config: &ConfigFile, realm: &str) -> String {
+	format!("cn={},ou=groups,{}", escape_dn_value(realm), config.ldap_base_dn)
+}
+
+/// The realms of the user that the service can see, all of them for users of the `all` realm
+fn visible_realms(user: &User, service: &LDAPService) -> Vec<String> {
+	service.realms
+		.iter()
+		.filter(|r| user.realms.contains(r) || user.realms.iter().any(|u| u == "all"))
+		.cloned()
+		.collect()
+}
+
+/// Every entry the service is allowed to see: the users of its realms as `inetOrgPerson`
+/// and the realms of the service as `groupOfNames`, along with the base and the organizational units
+pub fn get_entries(config: &ConfigFile, service: &LDAPService) -> Vec<Entry> {
+	let base_dn = &config.ldap_base_dn;
+	let users = config.users
+		.iter()
+		.filter(|u| u.has_any_realm(&service.realms))
+		.collect::<Vec<_>>();
+	let realms = users
+		.iter()
+		.flat_map(|u| visible_realms(u, service))
+		.collect::<BTreeSet<_>>();
+
+	let mut entries = vec![
+		Entry::new(base_dn.clone(), &[
+			("objectClass", vec!["top".to_string(), "domain".to_string()]),
+		]),
+		Entry::new(format!("ou=users,{}", base_dn), &[
+			("objectClass", vec!["top".to_string(), "organizationalUnit".to_string()]),
+			("ou", vec!["users".to_string()]),
+		]),
+		Entry::new(format!("ou=groups,{}", base_dn), &[
+			("objectClass", vec!["top".to_string(), "organizationalUnit".to_string()]),
+			("ou", vec!["groups".to_string()]),
+		]),
+	];
+
+	entries.extend(users.iter().map(|user| Entry::new(user_dn(config, &user.username), &[
+		("objectClass", ["top", "person", "organizationalPerson", "inetOrgPerson"].map(String::from).to_vec()),
+		("uid", vec![user.username.clone()]),
+		("cn", vec![user.name.clone()]),
+		("sn", vec![user.name.clone()]),
+		("displayName", vec![user.name.clone()]),
+		("mail", vec![user.email.clone()]),
+		("memberOf", visible_realms(user, service).iter().map(|r| group_dn(config, r)).collect()),
+	])));
+
+	entries.extend(realms.iter().map(|realm| Entry::new(group_dn(config, realm), &[
+		("objectClass", vec!["top".to_string(), "groupOfNames".to_string()]),
+		("cn", vec![realm.clone()]),
+		("member", users
+			.iter()
+			.filter(|u| visible_realms(u, service).contains(realm))
+			.map(|u| user_dn(config, &u.username))
+			.collect()),
+	])));
+
+	entries
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::CONFIG;
+
+	use ldap3_proto::parse_ldap_filter_str;
+
+	#[actix_web::test]
+	async fn test_directory() {
+		ConfigFile::reload().await.unwrap();
+		let config = CONFIG.read().await;
+		let service = config.ldap_services.first().unwrap();
+		let entries = get_entries(&config, service);
+		let base_dn = config.ldap_base_dn.clone();
+
+		let search_filter = |filter: LdapFilter, base: &str, scope: LdapSearchScope| {
+			entries
+				.iter()
+				.filter(|e| e.in_scope(base, &scope) && e.matches(&filter))
+				.map(|e| e.dn.clone())
+				.collect::<Vec<_>>()
+		};
+		let search = |filter: &str, base: &str, scope: LdapSearchScope| search_filter(parse_ldap_filter_str(filter).unwrap(), base, scope);
+
+		let user_dn = format!("uid=valid,ou=users,{}", base_dn);
+		assert_eq!(search("(&(objectClass=inetOrgPerson)(uid=VALID))", &base_dn, LdapSearchScope::Subtree), vec![user_dn.clone()]);
+		assert_eq!(search("(uid=val*)", &format!("ou=users, {}", base_dn), LdapSearchScope::OneLevel), vec![user_dn.clone()]);
+		assert_eq!(search("(uid=*)", &base_dn, LdapSearchScope::OneLevel), Vec::<String>::new());
+		assert_eq!(search("(objectClass=*)", &user_dn, LdapSearchScope::Base), vec![user_dn.clone()]);
+		let member = LdapFilter::Equality("member".to_string(), format!("UID=valid,ou=users,{}", base_dn));
+		assert_eq!(search_filter(member, &base_dn, LdapSearchScope::Subtree), vec![format!("cn=example,ou=groups,{}", base_dn)]);
+		assert!(search("(!(objectClass=inetOrgPerson))", &base_dn, LdapSearchScope::Subtree).iter().all(|dn| !dn.starts_with("uid=")));
+
+		let entry = entries.iter().find(|e| e.dn == user_dn).unwrap().to_result_entry(&["mail".to_string()]);
+		assert_eq!(entry.attributes.len(), 1);
+		assert_eq!(entry.attributes[0].vals, vec![b"valid@example.com".to_vec()]);
+
+		// Only the realms of the service are visible, even if the user has others
+		let integration_dn = format!("uid=integration,ou=users,{}", base_dn);
+		let integration = entries.iter().find(|e| e.dn == integration_dn).unwrap();
+		assert_eq!(integration.get("memberOf").unwrap(), &vec![format!("cn=example,ou=groups,{}", base_dn)]);
+		assert_eq!(search("(cn=public)", &base_dn, LdapSearchScope::Subtree), Vec::<String>::new());
+
+		// Values with special characters can't inject RDNs
+		assert_eq!(escape_dn_value("a,ou=admins+cn=x"), "a\\,ou\\=admins\\+cn\\=x");
+		assert_eq!(escape_dn_value(" #a\\b "), "\\ #a\\\\b\\ ");
+		let escaped_dn = super::user_dn(&config, "a,ou=groups");
+		assert_eq!(escaped_dn, format!("uid=a\\,ou\\=groups,ou=users,{}", base_dn));
+		let entry = Entry::new(escaped_dn, &[]);
+		assert!(entry.in_scope(&format!("ou=users,{}", base_dn), &LdapSearchScope::OneLevel));
+		assert!(!entry.in_scope(&format!("ou=groups,ou=users,{}", base_dn), &LdapSearchScope::Subtree));
+		assert_eq!(normalize_dn("UID = a\\, b\\ , OU=Users"), "uid=a\\, b\\ ,ou=users");
+	}
+}
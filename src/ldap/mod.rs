@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigFile;
+use crate::oidc::client::verify_secret;
+
+pub mod directory;
+pub mod server;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LDAPService {
+	/// The service binds as `cn={name},ou=services,{ldap_base_dn}`
+	pub name: String,
+	/// Either an argon2 (`$argon2id$...`) or bcrypt (`$2b$...`) hash, or plaintext
+	pub secret: String,
+	/// Only the users of these realms are visible to the service
+	pub realms: Vec<String>,
+}
+
+impl LDAPService {
+	pub fn from_bind<'a>(config: &'a ConfigFile, dn: &str, password: &str) -> Option<&'a Self> {
+		let dn = directory::normalize_dn(dn);
+
+		config.ldap_services
+			.iter()
+			.find(|s| directory::normalize_dn(&directory::service_dn(config, s)) == dn)
+			.filter(|s| verify_secret(&s.secret, password))
+	}
+}
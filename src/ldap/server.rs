@@ -0,0 +1,209 @@
+use futures::{SinkExt, StreamExt};
+use ldap3_proto::proto::{LdapMsg, LdapResultCode};
+use ldap3_proto::simple::{CompareRequest, DisconnectionNotice, SearchRequest, ServerOps, SimpleBindRequest, WhoamiRequest};
+use ldap3_proto::LdapCodec;
+use log::{debug, info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::ldap::directory::{get_entries, normalize_dn, service_dn, Entry};
+use crate::ldap::LDAPService;
+use crate::CONFIG;
+
+/// The state of a single connection, which starts out anonymous
+#[derive(Debug, Clone, Default)]
+pub struct LDAPSession {
+	/// The name of the bound service, looked up again on every operation
+	/// so that config changes apply to open connections as well
+	service: Option<String>,
+}
+
+impl LDAPSession {
+	async fn get_entries(&self) -> Option<Vec<Entry>> {
+		let config = CONFIG.read().await;
+		let name = self.service.as_ref()?;
+		let service = config.ldap_services.iter().find(|s| &s.name == name)?;
+
+		Some(get_entries(&config, service))
+	}
+
+	pub async fn bind(&mut self, req: &SimpleBindRequest) -> LdapMsg {
+		self.service = None;
+
+		// Anonymous binds are fine, they just can't see anything
+		if req.dn.is_empty() && req.pw.is_empty() {
+			return req.gen_success();
+		}
+
+		let config = CONFIG.read().await;
+		if let Some(service) = LDAPService::from_bind(&config, &req.dn, &req.pw) {
+			info!("LDAP service {} bound", &service.name);
+			self.service = Some(service.name.clone());
+			req.gen_success()
+		} else {
+			warn!("Invalid LDAP bind for {}", &req.dn);
+			req.gen_invalid_cred()
+		}
+	}
+
+	pub async fn search(&self, req: &SearchRequest) -> Vec<LdapMsg> {
+		let Some(entries) = self.get_entries().await else {
+			return vec![req.gen_error(LdapResultCode::InsufficentAccessRights, "Bind as a service first".to_string())];
+		};
+
+		let base = normalize_dn(&req.base);
+		if !entries.iter().any(|e| normalize_dn(&e.dn) == base) {
+			return vec![req.gen_error(LdapResultCode::NoSuchObject, format!("{} does not exist", &req.base))];
+		}
+
+		entries
+			.iter()
+			.filter(|e| e.in_scope(&req.base, &req.scope) && e.matches(&req.filter))
+			.map(|e| req.gen_result_entry(e.to_result_entry(&req.attrs)))
+			.chain(std::iter::once(req.gen_success()))
+			.collect()
+	}
+
+	pub async fn compare(&self, req: &CompareRequest) -> LdapMsg {
+		let Some(entries) = self.get_entries().await else {
+			return req.gen_error(LdapResultCode::InsufficentAccessRights, "Bind as a service first".to_string());
+		};
+
+		let dn = normalize_dn(&req.entry);
+		let Some(entry) = entries.iter().find(|e| normalize_dn(&e.dn) == dn) else {
+			return req.gen_error(LdapResultCode::NoSuchObject, format!("{} does not exist", &req.entry));
+		};
+
+		if entry.get(&req.atype).is_some_and(|values| values.iter().any(|v| v.eq_ignore_ascii_case(&req.val))) {
+			req.gen_compare_true()
+		} else {
+			req.gen_compare_false()
+		}
+	}
+
+	pub async fn whoami(&self, req: &WhoamiRequest) -> LdapMsg {
+		let config = CONFIG.read().await;
+		let service = self.service
+			.as_ref()
+			.and_then(|name| config.ldap_services.iter().find(|s| &s.name == name));
+
+		match service {
+			Some(service) => req.gen_success(&format!("dn: {}", service_dn(&config, service))),
+			None => req.gen_success(""),
+		}
+	}
+}
+
+async fn handle_client(socket: TcpStream) {
+	let (reader, writer) = tokio::io::split(socket);
+	let mut requests = FramedRead::new(reader, LdapCodec::default());
+	let mut responses = FramedWrite::new(writer, LdapCodec::default());
+	let mut session = LDAPSession::default();
+
+	while let Some(msg) = requests.next().await {
+		debug!("LDAP message: {:?}", msg);
+
+		let Ok(server_op) = msg.map_err(|_| ()).and_then(ServerOps::try_from) else {
+			// Anything that would modify the directory ends up here as well
+			let _ = responses.send(DisconnectionNotice::gen(LdapResultCode::UnwillingToPerform, "Unsupported operation, the directory is read-only")).await;
+			let _ = responses.flush().await;
+			return;
+		};
+
+		let results = match server_op {
+			ServerOps::SimpleBind(req) => vec![session.bind(&req).await],
+			ServerOps::Search(req) => session.search(&req).await,
+			ServerOps::Compare(req) => vec![session.compare(&req).await],
+			ServerOps::Whoami(req) => vec![session.whoami(&req).await],
+			// No response is sent to unbinds (RFC 4511 section 4.3)
+			ServerOps::Unbind(_) => return,
+		};
+
+		for result in results {
+			if responses.send(result).await.is_err() {
+				return;
+			}
+		}
+	}
+}
+
+/// Serves the directory on the listener until the process exits
+pub async fn serve(listener: TcpListener) {
+	loop {
+		match listener.accept().await {
+			Ok((socket, _)) => {
+				tokio::spawn(handle_client(socket));
+			},
+			Err(e) => warn!("Failed to accept LDAP connection: {}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use ldap3_proto::proto::{LdapBindCred, LdapBindRequest, LdapDerefAliases, LdapFilter, LdapOp, LdapSearchRequest, LdapSearchScope};
+
+	fn request(msgid: i32, op: LdapOp) -> LdapMsg {
+		LdapMsg { msgid, op, ctrl: vec![] }
+	}
+
+	fn search_request(base: &str, filter: LdapFilter) -> LdapOp {
+		LdapOp::SearchRequest(LdapSearchRequest {
+			base: base.to_string(),
+			scope: LdapSearchScope::Subtree,
+			aliases: LdapDerefAliases::Never,
+			sizelimit: 0,
+			timelimit: 0,
+			typesonly: false,
+			filter,
+			attrs: vec![],
+		})
+	}
+
+	fn bind_request(dn: &str, password: &str) -> LdapOp {
+		LdapOp::BindRequest(LdapBindRequest {
+			dn: dn.to_string(),
+			cred: LdapBindCred::Simple(password.to_string()),
+		})
+	}
+
+	#[actix_web::test]
+	async fn test_ldap_server() {
+		crate::config::ConfigFile::reload().await.unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(serve(listener));
+
+		let (reader, writer) = tokio::io::split(TcpStream::connect(addr).await.unwrap());
+		let mut responses = FramedRead::new(reader, LdapCodec::default());
+		let mut requests = FramedWrite::new(writer, LdapCodec::default());
+		let base_dn = CONFIG.read().await.ldap_base_dn.clone();
+		let filter = LdapFilter::Equality("mail".to_string(), "valid@example.com".to_string());
+
+		// Anonymous sessions can't search
+		requests.send(request(1, search_request(&base_dn, filter.clone()))).await.unwrap();
+		let LdapOp::SearchResultDone(result) = responses.next().await.unwrap().unwrap().op else { panic!() };
+		assert_eq!(result.code, LdapResultCode::InsufficentAccessRights);
+
+		requests.send(request(2, bind_request(&format!("cn=appliance,ou=services,{}", base_dn), "wrong"))).await.unwrap();
+		let LdapOp::BindResponse(result) = responses.next().await.unwrap().unwrap().op else { panic!() };
+		assert_eq!(result.res.code, LdapResultCode::InvalidCredentials);
+
+		requests.send(request(3, bind_request(&format!("cn=appliance,ou=services,{}", base_dn), "appliance_secret"))).await.unwrap();
+		let LdapOp::BindResponse(result) = responses.next().await.unwrap().unwrap().op else { panic!() };
+		assert_eq!(result.res.code, LdapResultCode::Success);
+
+		requests.send(request(4, search_request(&base_dn, filter))).await.unwrap();
+		let LdapOp::SearchResultEntry(entry) = responses.next().await.unwrap().unwrap().op else { panic!() };
+		assert_eq!(entry.dn, format!("uid=valid,ou=users,{}", base_dn));
+		assert!(entry.attributes.iter().any(|a| a.atype == "cn" && a.vals == vec![b"Valid User".to_vec()]));
+		let LdapOp::SearchResultDone(result) = responses.next().await.unwrap().unwrap().op else { panic!() };
+		assert_eq!(result.code, LdapResultCode::Success);
+
+		requests.send(request(5, search_request("dc=other", LdapFilter::Present("objectClass".to_string())))).await.unwrap();
+		let LdapOp::SearchResultDone(result) = responses.next().await.unwrap().unwrap().op else { panic!() };
+		assert_eq!(result.code, LdapResultCode::NoSuchObject);
+	}
+}
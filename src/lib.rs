@@ -6,12 +6,17 @@ use tokio::sync::RwLock;
 use crate::config::ConfigFile;
 
 pub mod auth_url;
+pub mod cas;
 pub mod config;
 #[cfg(feature = "kube")]
 pub mod config_kube;
 pub mod error;
+pub mod ldap;
 pub mod token;
 pub mod oidc;
+pub mod registry;
+pub mod saml;
+pub mod ssh_ca;
 pub mod user;
 pub mod utils;
 pub mod webauthn;
@@ -27,8 +32,11 @@ pub mod handle_static;
 pub mod tests;
 
 pub const AUTHORIZATION_COOKIE: &str = "oidc_authorization";
+pub const BROWSER_STATE_COOKIE: &str = "oidc_browser_state";
+pub const CAS_SERVICE_COOKIE: &str = "cas_service";
 pub const PROXIED_COOKIE: &str = "code";
 pub const RANDOM_STRING_LEN: usize = 32;
+pub const SAML_AUTHN_REQUEST_COOKIE: &str = "saml_authn_request";
 pub const SCOPED_LOGIN: &str = "scope";
 pub const SCOPED_SESSION_COOKIE: &str = "scoped_session_id";
 pub const SESSION_COOKIE: &str = "session_id";
@@ -6,7 +6,7 @@ use actix_session::storage::CookieSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::cookie::{Key, SameSite};
 use actix_web::{web, App, HttpServer};
-use actix_web::middleware::Logger;
+use actix_web::middleware::{Condition, Logger};
 use reindeer::Entity;
 #[cfg(feature = "kube")]
 use tokio::select;
@@ -19,11 +19,32 @@ pub async fn main() -> std::io::Result<()> {
 	#[cfg(debug_assertions)]
 	log::warn!("Running in debug mode, all magic links will be printed to the console.");
 
+	// CLI subcommands
+	if std::env::args().nth(1).as_deref() == Some("generate-client") {
+		let (id, secret, hash) = oidc::client::generate_client().expect("Failed to generate client");
+		println!("Client ID: {}", id);
+		println!("Client secret: {}", secret);
+		println!();
+		println!("Add the following to `oidc_clients` in the config, only the hash of the secret is stored:");
+		println!("- id: {}", id);
+		println!("  secret: \"{}\"", hash);
+		return Ok(());
+	}
+
 	ConfigFile::reload().await.expect("Failed to load config file");
 
 	let config = CONFIG.read().await;
 	let cookie_duration = config.session_duration.clone().to_std().expect("Couldn't parse session_duration");
 	let oidc_enable = config.oidc_enable.clone();
+	let cas_enable = config.cas_enable;
+	let registry_enable = config.registry_enable;
+	let saml_enable = config.saml_enable;
+	let ssh_ca_enable = config.ssh_ca_enable;
+	let ldap_listen = if config.ldap_enable {
+		Some(format!("{}:{}", config.listen_host, config.ldap_listen_port))
+	} else {
+		None
+	};
 	let webauthn_enable = config.webauthn_enable.clone();
 	let listen_host = config.listen_host.clone();
 	let listen_port = config.listen_port.clone();
@@ -34,6 +55,9 @@ pub async fn main() -> std::io::Result<()> {
 	config::ConfigKV::register(&db).expect("Failed to register config_kv entity");
 	token::register_token_kind(&db).expect("Failed to register token kinds");
 	webauthn::store::PasskeyStore::register(&db).expect("Failed to register passkey store");
+	oidc::dpop::DPoPProofJti::register(&db).expect("Failed to register DPoP proof store");
+	oidc::consumed_code::ConsumedOIDCCode::register(&db).expect("Failed to register consumed OIDC code store");
+	oidc::ciba::CIBARequest::register(&db).expect("Failed to register CIBA request store");
 
 	let secret = if let Ok(Some(secret_kv)) = ConfigKV::get(&ConfigKeys::Secret, &db) {
 		let secret = secret_kv.value.expect("Failed to load secret from database");
@@ -67,6 +91,12 @@ pub async fn main() -> std::io::Result<()> {
 
 	// OIDC setup
 	let oidc_key = oidc::init(&db).await;
+
+	// SAML setup
+	let saml_keys = saml::init(&db).await;
+
+	// SSH CA setup
+	let ssh_ca_key = ssh_ca::init(&db).await;
 	drop(config);
 
 	let server = HttpServer::new(move || {
@@ -77,6 +107,8 @@ pub async fn main() -> std::io::Result<()> {
 			.app_data(web::Data::new(db.clone()))
 			.app_data(web::Data::new(mailer.clone()))
 			.app_data(web::Data::new(http_client.clone()))
+			// Signs the OIDC and the registry tokens
+			.app_data(web::Data::new(oidc_key.clone()))
 
 			.default_service(web::route().to(error::not_found))
 
@@ -89,10 +121,13 @@ pub async fn main() -> std::io::Result<()> {
 			.service(handle_static::static_files)
 			.service(handle_static::favicon)
 
+			.service(auth_url::handle_ext_authz::ext_authz)
+			.service(auth_url::handle_forward_auth::forward_auth)
 			.service(auth_url::handle_status::status)
 			.service(auth_url::handle_response::response)
 
 			// Middleware
+			.wrap(Condition::new(oidc_enable, oidc::cors::cors()))
 			.wrap(Logger::default())
 			.wrap(
 				SessionMiddleware::builder(
@@ -110,13 +145,50 @@ pub async fn main() -> std::io::Result<()> {
 
 		// OIDC routes
 		if oidc_enable {
-			app = app.app_data(web::Data::new(oidc_key.clone()))
+			app = app
 				.service(oidc::handle_discover::discover)
 				.service(oidc::handle_authorize::authorize_get)
 				.service(oidc::handle_authorize::authorize_post)
+				.service(oidc::handle_check_session::check_session)
 				.service(oidc::handle_token::token)
+				.service(oidc::handle_introspect::introspect)
 				.service(oidc::handle_jwks::jwks)
-				.service(oidc::handle_userinfo::userinfo);
+				.service(oidc::handle_userinfo::userinfo)
+				.service(oidc::handle_webfinger::webfinger)
+				.service(oidc::ciba::backchannel_authorize)
+				.service(oidc::ciba::backchannel_approve_page)
+				.service(oidc::ciba::backchannel_approve);
+		}
+
+		// CAS routes
+		if cas_enable {
+			app = app
+				.service(cas::handle_login::login)
+				.service(cas::handle_validate::service_validate)
+				.service(cas::handle_validate::service_validate_p3)
+				.service(cas::handle_logout::logout);
+		}
+
+		// Docker registry routes
+		if registry_enable {
+			app = app.service(registry::handle_token::token);
+		}
+
+		// SAML routes
+		if saml_enable {
+			app = app.app_data(web::Data::new(saml_keys.clone()))
+				.service(saml::handle_metadata::metadata)
+				.service(saml::handle_sso::sso_redirect)
+				.service(saml::handle_sso::sso_post)
+				.service(saml::handle_sso::resume);
+		}
+
+		// SSH CA routes
+		if ssh_ca_enable {
+			app = app.app_data(web::Data::new(ssh_ca_key.clone()))
+				.service(ssh_ca::handle_ca::ca)
+				.service(ssh_ca::handle_sign::sign_get)
+				.service(ssh_ca::handle_sign::sign_post);
 		}
 
 		if webauthn_enable {
@@ -134,6 +206,13 @@ pub async fn main() -> std::io::Result<()> {
 	.unwrap()
 	.run();
 
+	// LDAP setup
+	if let Some(ldap_listen) = ldap_listen {
+		let listener = tokio::net::TcpListener::bind(&ldap_listen).await.expect("Failed to bind the LDAP listener");
+		log::info!("LDAP directory listening on {}", &ldap_listen);
+		tokio::spawn(ldap::server::serve(listener));
+	}
+
 	let _config_watcher = config::ConfigFile::watch();
 
 	#[cfg(feature = "kube")]
@@ -0,0 +1,390 @@
+use std::collections::BTreeMap;
+
+use actix_web::http::header::ContentType;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use jwt_simple::algorithms::RS256KeyPair;
+use log::{info, warn};
+use reindeer::{Db, Entity};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppErrorKind, Response, Result};
+use crate::handle_login_action::send_link;
+use crate::oidc::client::{BackchannelTokenDeliveryMode, OIDCClient};
+use crate::oidc::handle_authorize::AuthorizeRequest;
+use crate::oidc::handle_token::{token_error_response, BearerTokenMetadata, TokenRequest, TokenResponse};
+use crate::user::User;
+use crate::utils::{get_partial, random_string};
+use crate::{SmtpTransport, CONFIG};
+
+pub const CIBA_GRANT_TYPE: &str = "urn:openid:params:grant-type:ciba";
+/// The minimum seconds between two polls of the token endpoint
+pub const CIBA_POLL_INTERVAL: i64 = 5;
+/// How long the client notification endpoint has to answer the ping
+const CIBA_PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum CIBAStatus {
+	Pending,
+	Approved,
+	Denied,
+}
+
+/// A backchannel authentication request waiting for the user to approve or deny it in the browser
+#[derive(Entity, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[entity(name = "ciba_request", id = "auth_req_id", version = 1)]
+pub struct CIBARequest {
+	/// Given to the client to get the tokens with
+	pub auth_req_id: String,
+	/// Part of the approval link sent to the user, kept apart from the `auth_req_id` that the client knows
+	pub approval_code: String,
+	#[serde(with = "crate::user::as_string")]
+	pub user: User,
+	pub client_id: String,
+	pub scope: String,
+	/// Shown to the user, so that they can tell that the request is the one they're expecting
+	pub binding_message: Option<String>,
+	/// The bearer token of the ping to the client notification endpoint
+	pub client_notification_token: Option<String>,
+	pub status: CIBAStatus,
+	pub expires_at: NaiveDateTime,
+	pub last_polled_at: Option<NaiveDateTime>,
+}
+
+impl CIBARequest {
+	pub fn from_auth_req_id(db: &Db, auth_req_id: &String) -> Result<Self> {
+		let now = Utc::now().naive_utc();
+		Self::filter_remove(|r| r.expires_at <= now, db)?;
+
+		Self::get(auth_req_id, db)?.ok_or(AppErrorKind::CIBARequestExpired.into())
+	}
+
+	pub fn from_approval_code(db: &Db, approval_code: &str) -> Result<Self> {
+		let now = Utc::now().naive_utc();
+		Self::filter_remove(|r| r.expires_at <= now, db)?;
+
+		Self::get_with_filter(|r| r.approval_code == approval_code, db)?
+			.into_iter()
+			.next()
+			.ok_or(AppErrorKind::NotFound.into())
+	}
+
+	pub fn expires_in(&self) -> i64 {
+		(self.expires_at - Utc::now().naive_utc()).num_seconds()
+	}
+
+	/// Lets a ping mode client know that the user has decided
+	async fn ping(&self, http_client: &reqwest::Client, client: &OIDCClient) -> Result<()> {
+		let (Some(endpoint), Some(notification_token)) = (&client.backchannel_client_notification_endpoint, &self.client_notification_token) else {
+			return Ok(());
+		};
+
+		let mut body = BTreeMap::new();
+		body.insert("auth_req_id", &self.auth_req_id);
+
+		let resp = http_client
+			.post(endpoint)
+			.timeout(CIBA_PING_TIMEOUT)
+			.bearer_auth(notification_token)
+			.header(reqwest::header::CONTENT_TYPE, "application/json")
+			.body(serde_json::to_string(&body)?)
+			.send()
+			.await?;
+
+		if !resp.status().is_success() {
+			warn!("CIBA ping to client {} failed: {}", &client.id, resp.status());
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BackchannelAuthenticationRequest {
+	pub client_id: Option<String>,
+	pub client_secret: Option<String>,
+	pub scope: String,
+	pub login_hint: Option<String>,
+	pub binding_message: Option<String>,
+	pub client_notification_token: Option<String>,
+	/// In seconds, can only shorten the configured `link_duration`
+	pub requested_expiry: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BackchannelAuthenticationResponse {
+	pub auth_req_id: String,
+	pub expires_in: i64,
+	pub interval: i64,
+}
+
+/// The backchannel authentication endpoint (CIBA section 7).
+/// The user gets an approval link over the same channels as the magic links.
+#[post("/oidc/bc-authorize")]
+pub async fn backchannel_authorize(db: web::Data<reindeer::Db>, mailer: web::Data<Option<SmtpTransport>>, http_client: web::Data<Option<reqwest::Client>>, bc_req: web::Form<BackchannelAuthenticationRequest>) -> Response {
+	token_error_response(backchannel_authorize_inner(db, mailer, http_client, bc_req.into_inner()).await)
+}
+
+async fn backchannel_authorize_inner(db: web::Data<reindeer::Db>, mailer: web::Data<Option<SmtpTransport>>, http_client: web::Data<Option<reqwest::Client>>, bc_req: BackchannelAuthenticationRequest) -> Response {
+	let config = CONFIG.read().await;
+	let client_id = bc_req.client_id.as_ref().ok_or(AppErrorKind::NoClientID)?;
+	let client_secret = bc_req.client_secret.as_ref().ok_or(AppErrorKind::NoClientCredentialsProvided)?;
+	let client = OIDCClient::from_credentials(&config, client_id, client_secret)?.clone();
+
+	let Some(delivery_mode) = client.backchannel_token_delivery_mode else {
+		return Err(AppErrorKind::CIBANotAllowed.into());
+	};
+
+	if delivery_mode == BackchannelTokenDeliveryMode::Ping && bc_req.client_notification_token.is_none() {
+		return Err(AppErrorKind::MissingClientNotificationToken.into());
+	}
+
+	// The ID token is the whole point of CIBA (CIBA section 7.1)
+	if !bc_req.scope.split_whitespace().any(|s| s == "openid") {
+		return Err(AppErrorKind::InvalidScope.into());
+	}
+	client.check_scope(&bc_req.scope)?;
+
+	let login_hint = bc_req.login_hint.as_ref().ok_or(AppErrorKind::MissingLoginHint)?;
+	let user = User::from_config(login_hint).await
+		.filter(|u| u.has_any_realm(&client.realms))
+		.ok_or(AppErrorKind::UnknownUserID)?;
+
+	let mut expiry = config.link_duration;
+	if let Some(requested_expiry) = bc_req.requested_expiry {
+		expiry = expiry.min(chrono::Duration::try_seconds(requested_expiry).ok_or(AppErrorKind::InvalidDuration)?);
+	}
+
+	let ciba_req = CIBARequest {
+		auth_req_id: random_string(),
+		approval_code: random_string(),
+		user: user.clone(),
+		client_id: client.id.clone(),
+		scope: bc_req.scope.clone(),
+		binding_message: bc_req.binding_message.clone(),
+		client_notification_token: bc_req.client_notification_token.clone(),
+		status: CIBAStatus::Pending,
+		expires_at: Utc::now().naive_utc().checked_add_signed(expiry).ok_or(AppErrorKind::InvalidDuration)?,
+		last_polled_at: None,
+	};
+	ciba_req.save(&db)?;
+
+	// The request comes from the client's backend, the link has to work for the user
+	let approval_link = format!("{}/oidc/bc-approve/{}", config.external_url, ciba_req.approval_code);
+	drop(config);
+
+	#[cfg(debug_assertions)]
+	println!("CIBA approval link: {}", &approval_link);
+
+	info!("Client {} requested a backchannel authentication of {}", &client.id, &user.email);
+	send_link(&mailer, &http_client, &user, &approval_link).await?;
+
+	Ok(HttpResponse::Ok().json(BackchannelAuthenticationResponse {
+		expires_in: ciba_req.expires_in(),
+		auth_req_id: ciba_req.auth_req_id,
+		interval: CIBA_POLL_INTERVAL,
+	}))
+}
+
+#[get("/oidc/bc-approve/{approval_code}")]
+pub async fn backchannel_approve_page(db: web::Data<reindeer::Db>, approval_code: web::Path<String>) -> Response {
+	let ciba_req = CIBARequest::from_approval_code(&db, &approval_code)?;
+	let config = CONFIG.read().await;
+	let client = OIDCClient::from_id(&config, &ciba_req.client_id)?;
+
+	let mut approve_data = BTreeMap::new();
+	approve_data.insert("client", client.name.clone().unwrap_or(client.id.clone()));
+	approve_data.insert("email", ciba_req.user.email.clone());
+	if ciba_req.status == CIBAStatus::Pending {
+		approve_data.insert("pending", "true".to_string());
+	}
+	if let Some(binding_message) = &ciba_req.binding_message {
+		approve_data.insert("binding_message", binding_message.clone());
+	}
+	let approve_page = get_partial("ciba", approve_data)?;
+
+	Ok(HttpResponse::Ok()
+		.content_type(ContentType::html())
+		.body(approve_page))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BackchannelApproval {
+	pub approve: bool,
+}
+
+#[post("/oidc/bc-approve/{approval_code}")]
+pub async fn backchannel_approve(db: web::Data<reindeer::Db>, http_client: web::Data<Option<reqwest::Client>>, approval_code: web::Path<String>, approval: web::Form<BackchannelApproval>) -> Response {
+	let mut ciba_req = CIBARequest::from_approval_code(&db, &approval_code)?;
+
+	// A decision can't be changed, the client might have already gotten its tokens
+	if ciba_req.status == CIBAStatus::Pending {
+		ciba_req.status = if approval.approve { CIBAStatus::Approved } else { CIBAStatus::Denied };
+		ciba_req.save(&db)?;
+		info!("User {} {:?} the backchannel authentication of {}", &ciba_req.user.email, ciba_req.status, &ciba_req.client_id);
+
+		let client = OIDCClient::from_id(&*CONFIG.read().await, &ciba_req.client_id)?.clone();
+		if client.backchannel_token_delivery_mode == Some(BackchannelTokenDeliveryMode::Ping) {
+			// The decision is saved already, the user doesn't have to wait for the client nor see its failures
+			let http_client = http_client.as_ref().clone().unwrap_or_default();
+			actix_web::rt::spawn(async move {
+				if let Err(e) = ciba_req.ping(&http_client, &client).await {
+					warn!("CIBA ping to client {} failed: {}", &client.id, e);
+				}
+			});
+		}
+	}
+
+	Ok(HttpResponse::Found()
+		.append_header(("Location", format!("/oidc/bc-approve/{}", approval_code)))
+		.finish())
+}
+
+/// The CIBA grant of the token endpoint (CIBA section 10.1), for both poll and ping mode clients
+pub async fn ciba(req: HttpRequest, db: web::Data<reindeer::Db>, token_req: TokenRequest, jwt_keypair: web::Data<RS256KeyPair>) -> Response {
+	let auth_req_id = token_req.auth_req_id.as_ref().ok_or(AppErrorKind::MissingAuthReqID)?;
+	let config = CONFIG.read().await;
+	let client_id = token_req.client_id.as_ref().ok_or(AppErrorKind::NoClientID)?;
+	let client_secret = token_req.client_secret.as_ref().ok_or(AppErrorKind::NoClientCredentialsProvided)?;
+	let client = OIDCClient::from_credentials(&config, client_id, client_secret)?.clone();
+
+	let mut ciba_req = CIBARequest::from_auth_req_id(&db, auth_req_id)?;
+	if ciba_req.client_id != client.id {
+		return Err(AppErrorKind::NotMatchingClientID.into());
+	}
+
+	match ciba_req.status {
+		CIBAStatus::Pending => {
+			let now = Utc::now().naive_utc();
+			let too_fast = ciba_req.last_polled_at.is_some_and(|last| (now - last).num_seconds() < CIBA_POLL_INTERVAL);
+			ciba_req.last_polled_at = Some(now);
+			ciba_req.save(&db)?;
+
+			return Err(if too_fast { AppErrorKind::CIBASlowDown } else { AppErrorKind::CIBAAuthorizationPending }.into());
+		},
+		CIBAStatus::Denied => {
+			CIBARequest::remove(&ciba_req.auth_req_id, &db)?;
+			return Err(AppErrorKind::CIBAAccessDenied.into());
+		},
+		CIBAStatus::Approved => CIBARequest::remove(&ciba_req.auth_req_id, &db)?,
+	}
+
+	let auth_req = AuthorizeRequest {
+		scope: ciba_req.scope.clone(),
+		client_id: client.id.clone(),
+		..Default::default()
+	};
+	let base_url = config.url_from_request(&req);
+	let id_token = auth_req.generate_id_token(&ciba_req.user, base_url, jwt_keypair.as_ref(), client.get_id_token_duration(&config)).await?;
+	drop(config);
+
+	let bearer_metadata = BearerTokenMetadata {
+		client_id: client.id.clone(),
+		audience: vec![],
+		jkt: None,
+		scope: Some(ciba_req.scope.clone()),
+	};
+	let (bearer, refresh) = bearer_metadata.issue(&db, &client, ciba_req.user.clone(), None).await?;
+
+	info!("Client {} got the tokens of the backchannel authentication of {}", &client.id, &ciba_req.user.email);
+
+	Ok(HttpResponse::Ok().json(TokenResponse {
+		access_token: bearer.code.clone(),
+		token_type: bearer_metadata.token_type(),
+		expires_in: bearer.expires_in(),
+		id_token: Some(id_token),
+		refresh_token: refresh.map(|r| r.code),
+		issued_token_type: None,
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::oidc::handle_token::{self, TokenErrorResponse};
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_ciba() {
+		let db = &db_connect().await;
+		get_valid_user().await;
+		let keypair = RS256KeyPair::generate(2048).unwrap();
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(keypair.clone()))
+				.app_data(web::Data::new(None::<SmtpTransport>))
+				.app_data(web::Data::new(None::<reqwest::Client>))
+				.service(backchannel_authorize)
+				.service(backchannel_approve_page)
+				.service(backchannel_approve)
+				.service(handle_token::token)
+		)
+		.await;
+
+		let bc_request = |login_hint: &str| actix_test::TestRequest::post()
+			.uri("/oidc/bc-authorize")
+			.set_form(&BackchannelAuthenticationRequest {
+				client_id: Some("my_client".to_string()),
+				client_secret: Some("my_secret".to_string()),
+				scope: "openid email".to_string(),
+				login_hint: Some(login_hint.to_string()),
+				binding_message: Some("W4SCT".to_string()),
+				..Default::default()
+			})
+			.to_request();
+
+		let resp = actix_test::call_service(&mut app, bc_request("invalid@example.com")).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+		let body: TokenErrorResponse = actix_test::read_body_json(resp).await;
+		assert_eq!(body.error, "unknown_user_id");
+
+		let resp = actix_test::call_service(&mut app, bc_request("valid@example.com")).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let bc_resp: BackchannelAuthenticationResponse = actix_test::read_body_json(resp).await;
+		assert_eq!(bc_resp.interval, CIBA_POLL_INTERVAL);
+
+		let token_request = || actix_test::TestRequest::post()
+			.uri("/oidc/token")
+			.set_form(&TokenRequest {
+				grant_type: CIBA_GRANT_TYPE.to_string(),
+				client_id: Some("my_client".to_string()),
+				client_secret: Some("my_secret".to_string()),
+				auth_req_id: Some(bc_resp.auth_req_id.clone()),
+				..Default::default()
+			})
+			.to_request();
+
+		let body: TokenErrorResponse = actix_test::call_and_read_body_json(&mut app, token_request()).await;
+		assert_eq!(body.error, "authorization_pending");
+		let body: TokenErrorResponse = actix_test::call_and_read_body_json(&mut app, token_request()).await;
+		assert_eq!(body.error, "slow_down");
+
+		// The user approves through the link they got
+		let approval_code = CIBARequest::get(&bc_resp.auth_req_id, db).unwrap().unwrap().approval_code;
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/oidc/bc-approve/{}", approval_code).as_str())
+			.to_request();
+		let body = actix_test::call_and_read_body(&mut app, req).await;
+		assert!(std::str::from_utf8(&body).unwrap().contains("W4SCT"));
+
+		let req = actix_test::TestRequest::post()
+			.uri(format!("/oidc/bc-approve/{}", approval_code).as_str())
+			.set_form(&BackchannelApproval { approve: true })
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+
+		let resp = actix_test::call_service(&mut app, token_request()).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body: TokenResponse = actix_test::read_body_json(resp).await;
+		assert!(body.id_token.is_some());
+
+		// The auth_req_id can only be used once
+		let body: TokenErrorResponse = actix_test::call_and_read_body_json(&mut app, token_request()).await;
+		assert_eq!(body.error, "expired_token");
+	}
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppErrorKind, Result};
+use crate::user::User;
+
+/// The claims that can be returned about a user
+pub const SUPPORTED_CLAIMS: [&str; 5] = [
+	"sub",
+	"name",
+	"email",
+	"email_verified",
+	"preferred_username",
+];
+
+/// A single requested claim - `null` in the request means a voluntary claim with the defaults
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClaimRequest {
+	#[serde(default)]
+	pub essential: bool,
+	pub value: Option<serde_json::Value>,
+	pub values: Option<Vec<serde_json::Value>>,
+}
+
+impl ClaimRequest {
+	/// Whether the value is one of the requested ones, if any were requested
+	pub fn allows(&self, value: &serde_json::Value) -> bool {
+		self.value.as_ref().map_or(true, |v| v == value) &&
+		self.values.as_ref().map_or(true, |values| values.contains(value))
+	}
+}
+
+/// The `claims` authorize request parameter (OIDC Core 1.0 section 5.5)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClaimsRequest {
+	#[serde(default)]
+	pub userinfo: BTreeMap<String, Option<ClaimRequest>>,
+	#[serde(default)]
+	pub id_token: BTreeMap<String, Option<ClaimRequest>>,
+}
+
+impl ClaimsRequest {
+	pub fn parse(claims: &str) -> Result<Self> {
+		serde_json::from_str(claims).map_err(|_| AppErrorKind::InvalidClaimsRequest.into())
+	}
+
+	/// A `sub` requested with a value asks for a specific user, no tokens can be issued
+	/// for anyone else, even if they're logged in (OIDC Core 1.0 section 5.5.1)
+	pub fn check_subject(&self, user: &User) -> Result<()> {
+		let sub = get_user_claim(user, "sub").unwrap_or_default();
+		let allowed = [&self.id_token, &self.userinfo]
+			.into_iter()
+			.filter_map(|claims| claims.get("sub").cloned().flatten())
+			.all(|request| request.allows(&sub));
+
+		if allowed {
+			Ok(())
+		} else {
+			warn!("The claims request asked for another sub than {}", user.email);
+			Err(AppErrorKind::SubjectMismatch.into())
+		}
+	}
+
+	/// The requested claims of the ID token that the user has.
+	/// Claims that can't be provided are left out, even essential ones,
+	/// as the spec forbids returning an error for them (OIDC Core 1.0 section 5.5.1)
+	pub fn get_id_token_claims(&self, user: &User) -> BTreeMap<String, serde_json::Value> {
+		self.id_token
+			.iter()
+			// These are always part of the ID token
+			.filter(|(name, _)| !["sub", "aud", "iss", "exp", "iat"].contains(&name.as_str()))
+			.filter_map(|(name, request)| {
+				let value = get_user_claim(user, name);
+
+				if value.is_none() && request.as_ref().is_some_and(|r| r.essential) {
+					warn!("Essential claim {} is not available for {}", name, user.email);
+				}
+
+				Some((name.clone(), value?))
+			})
+			.collect()
+	}
+}
+
+pub fn get_user_claim(user: &User, name: &str) -> Option<serde_json::Value> {
+	match name {
+		"sub" | "email" => Some(user.email.clone().into()),
+		"name" => Some(user.name.clone().into()),
+		"email_verified" => Some(true.into()),
+		"preferred_username" => Some(user.username.clone().into()),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::tests::*;
+
+	#[actix_web::test]
+	async fn test_claims_request() {
+		let user = get_valid_user().await;
+
+		let claims = ClaimsRequest::parse(r#"{
+			"userinfo": {"email": null},
+			"id_token": {
+				"email": {"essential": true},
+				"preferred_username": null,
+				"phone_number": {"essential": true},
+				"sub": {"value": "valid@example.com"}
+			}
+		}"#).unwrap();
+
+		assert!(claims.userinfo.contains_key("email"));
+		assert!(claims.check_subject(&user).is_ok());
+		assert_eq!(claims.get_id_token_claims(&user), BTreeMap::from([
+			("email".to_string(), "valid@example.com".into()),
+			("preferred_username".to_string(), "valid".into()),
+		]));
+
+		// No tokens for a user other than the requested one
+		let other_sub = ClaimsRequest::parse(r#"{"id_token": {"sub": {"value": "other@example.com"}}}"#).unwrap();
+		assert!(other_sub.check_subject(&user).unwrap_err().is(AppErrorKind::SubjectMismatch));
+		let other_subs = ClaimsRequest::parse(r#"{"userinfo": {"sub": {"values": ["a@example.com", "b@example.com"]}}}"#).unwrap();
+		assert!(other_subs.check_subject(&user).is_err());
+		let any_sub = ClaimsRequest::parse(r#"{"id_token": {"sub": {"essential": true}}}"#).unwrap();
+		assert!(any_sub.check_subject(&user).is_ok());
+
+		assert!(ClaimsRequest::parse("not json").is_err());
+		assert!(ClaimsRequest::parse("{}").unwrap().get_id_token_claims(&user).is_empty());
+	}
+}
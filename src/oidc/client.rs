@@ -1,22 +1,348 @@
+use actix_web::http::Uri;
+use chrono::Duration;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use log::warn;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 
+use crate::config::ConfigFile;
 use crate::token::OIDCCodeToken;
 use crate::user::User;
+use crate::utils::random_string;
 use crate::CONFIG;
-use crate::error::Result;
+use crate::error::{AppErrorKind, Result};
 
 use super::handle_authorize::AuthorizeRequest;
+use super::SUPPORTED_SCOPES;
+
+/// The client types of RFC 6749 section 2.1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientType {
+	/// Can't keep a secret (SPAs, native apps), has to use S256 PKCE instead
+	Public,
+	/// Has a secret and always has to authenticate with it
+	#[default]
+	Confidential,
+}
+
+/// How a CIBA client gets notified about the outcome of a backchannel authentication request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackchannelTokenDeliveryMode {
+	/// The client polls the token endpoint until the user decides
+	#[default]
+	Poll,
+	/// The client gets a request to its notification endpoint and then calls the token endpoint
+	Ping,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct OIDCClient {
 	pub id: String,
-	pub secret: String,
+	#[serde(default)]
+	pub client_type: ClientType,
+	/// Only confidential clients have a secret.
+	/// Either an argon2 (`$argon2id$...`) or bcrypt (`$2b$...`) hash, or plaintext
+	#[serde(default)]
+	pub secret: Option<String>,
+	/// Require PKCE from a confidential client as well - public clients always need it
+	#[serde(default)]
+	pub require_pkce: bool,
+	/// Accept the `plain` code challenge method, never accepted for public clients
+	#[serde(default)]
+	pub allow_plain_pkce: bool,
+	/// How the client is presented to the user on the authorize page, along with its logo and links
+	#[serde(default)]
+	pub name: Option<String>,
+	#[serde(default)]
+	pub logo_uri: Option<String>,
+	#[serde(default)]
+	pub client_uri: Option<String>,
+	#[serde(default)]
+	pub policy_uri: Option<String>,
+	#[serde(default)]
+	pub tos_uri: Option<String>,
+	/// Send the magic link to the `login_hint` of the authorize request straight away,
+	/// instead of showing the login form pre-filled with it
+	#[serde(default)]
+	pub send_login_hint: bool,
 	pub redirect_uris: Vec<String>,
+	/// Redirect URI patterns where `*` matches part of a single DNS label of the host, for example
+	/// `https://*.preview.example.com/callback` for preview deployments. The rest must match exactly
+	#[serde(default)]
+	pub redirect_uri_patterns: Vec<String>,
+	/// Extra origins that may call the OIDC endpoints from the browser,
+	/// on top of the origins of the `redirect_uris`
+	#[serde(default)]
+	pub cors_origins: Vec<String>,
 	pub realms: Vec<String>,
+	/// The client IDs that this client is allowed to exchange user tokens for (RFC 8693)
+	#[serde(default)]
+	pub token_exchange_targets: Vec<String>,
+	/// The resources (RFC 8707) that this client is allowed to request tokens for,
+	/// also the resources that this client is allowed to introspect tokens of
+	#[serde(default)]
+	pub resources: Vec<String>,
+	/// Reject token requests without a DPoP proof (RFC 9449), binding all the issued tokens to the client's key
+	#[serde(default)]
+	pub dpop_bound_access_tokens: bool,
+	/// Allows the client to authenticate users over the backchannel (CIBA), only for confidential clients
+	#[serde(default)]
+	pub backchannel_token_delivery_mode: Option<BackchannelTokenDeliveryMode>,
+	/// Where the ping of the ping delivery mode is sent
+	#[serde(default)]
+	pub backchannel_client_notification_endpoint: Option<String>,
+	/// The scopes that the client may request - empty means all the supported scopes
+	#[serde(default)]
+	pub allowed_scopes: Vec<String>,
+	/// Overrides the global `session_duration` for the access tokens of the client
+	#[serde(default, deserialize_with = "duration_str::deserialize_option_duration_chrono", skip_serializing)]
+	pub access_token_duration: Option<Duration>,
+	/// Overrides the global `session_duration` for the ID tokens of the client
+	#[serde(default, deserialize_with = "duration_str::deserialize_option_duration_chrono", skip_serializing)]
+	pub id_token_duration: Option<Duration>,
+	/// Refresh tokens are only issued to clients that set their duration
+	#[serde(default, deserialize_with = "duration_str::deserialize_option_duration_chrono", skip_serializing)]
+	pub refresh_token_duration: Option<Duration>,
+}
+
+/// How a requested redirect URI matched the ones registered for the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectUriMatch {
+	/// Exact match with one of the `redirect_uris`
+	Exact,
+	/// Loopback or private-use URI scheme redirect of a native app (RFC 8252)
+	Native,
+	/// Match with one of the `redirect_uri_patterns`
+	Pattern,
+}
+
+impl RedirectUriMatch {
+	/// Native apps can't keep a secret and patterns are too loose to trust the redirect alone,
+	/// so the code has to be bound to the client with PKCE
+	pub fn requires_pkce(&self) -> bool {
+		*self != RedirectUriMatch::Exact
+	}
+}
+
+fn is_loopback(uri: &Uri) -> bool {
+	uri.scheme_str() == Some("http") && matches!(uri.host(), Some("127.0.0.1") | Some("[::1]"))
+}
+
+/// Private-use URI schemes are reverse domain names (RFC 8252 section 7.1), e.g. `com.example.app:/callback`
+fn is_private_use_scheme(redirect_uri: &str) -> bool {
+	redirect_uri
+		.split_once(':')
+		.is_some_and(|(scheme, _)| scheme.contains('.'))
+}
+
+/// Matches a DNS label against a pattern label, where `*` stands for any (possibly empty) run of label characters
+fn matches_label(pattern: &str, label: &str) -> bool {
+	if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+		return false;
+	}
+
+	match pattern.split_once('*') {
+		None => pattern.eq_ignore_ascii_case(label),
+		Some((prefix, suffix)) =>
+			label.len() >= prefix.len() + suffix.len() &&
+			label[..prefix.len()].eq_ignore_ascii_case(prefix) &&
+			label[label.len() - suffix.len()..].eq_ignore_ascii_case(suffix),
+	}
+}
+
+/// Matches a redirect URI against a pattern with wildcards in its host only.
+/// The URI is parsed first, its host is compared one DNS label at a time,
+/// and the scheme, port, path and query have to be exactly the ones of the pattern.
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+	let Some((pattern_scheme, pattern_rest)) = pattern.split_once("://") else {
+		return false;
+	};
+	let authority_len = pattern_rest.find(['/', '?', '#']).unwrap_or(pattern_rest.len());
+	let (pattern_authority, pattern_path) = pattern_rest.split_at(authority_len);
+	let (pattern_host, pattern_port) = match pattern_authority.rsplit_once(':') {
+		Some((host, port)) => (host, port.parse::<u16>().ok()),
+		None => (pattern_authority, None),
+	};
+	let pattern_path = if pattern_path.is_empty() { "/" } else { pattern_path };
+	if pattern_path.contains('*') || pattern_authority.contains('@') {
+		return false;
+	}
+
+	let Ok(uri) = value.parse::<Uri>() else {
+		return false;
+	};
+	let (Some(scheme), Some(authority), Some(host)) = (uri.scheme_str(), uri.authority(), uri.host()) else {
+		return false;
+	};
+
+	let pattern_labels = pattern_host.split('.').collect::<Vec<_>>();
+	let labels = host.split('.').collect::<Vec<_>>();
+
+	scheme.eq_ignore_ascii_case(pattern_scheme) &&
+	!authority.as_str().contains('@') &&
+	uri.port_u16() == pattern_port &&
+	uri.path_and_query().map(|p| p.as_str()) == Some(pattern_path) &&
+	pattern_labels.len() == labels.len() &&
+	pattern_labels.iter().zip(labels).all(|(p, l)| matches_label(p, l))
+}
+
+/// Checks a client secret against the configured one, which might be hashed.
+/// Plaintext secrets are hashed before comparing, so that neither their contents
+/// nor their length leak through the timing of the comparison.
+pub fn verify_secret(config_secret: &str, client_secret: &str) -> bool {
+	if config_secret.starts_with("$argon2") {
+		let Ok(hash) = PasswordHash::new(config_secret) else {
+			warn!("Invalid argon2 hash in the config");
+			return false;
+		};
+
+		Argon2::default().verify_password(client_secret.as_bytes(), &hash).is_ok()
+	} else if config_secret.starts_with("$2") {
+		bcrypt::verify(client_secret, config_secret).unwrap_or_else(|e| {
+			warn!("Invalid bcrypt hash in the config: {}", e);
+			false
+		})
+	} else {
+		Sha256::digest(config_secret.as_bytes())
+			.ct_eq(&Sha256::digest(client_secret.as_bytes()))
+			.into()
+	}
+}
+
+/// Generates a new client ID, secret and the argon2 hash of the secret for the config
+pub fn generate_client() -> Result<(String, String, String)> {
+	let id = random_string();
+	let secret = random_string();
+	let salt = SaltString::generate(&mut rand::rngs::OsRng);
+	let hash = Argon2::default()
+		.hash_password(secret.as_bytes(), &salt)
+		.map_err(|e| format!("Failed to hash the client secret: {}", e))?
+		.to_string();
+
+	Ok((id, secret, hash))
 }
 
 impl OIDCClient {
+	pub fn from_id<'a>(config: &'a ConfigFile, client_id: &str) -> Result<&'a OIDCClient> {
+		config.oidc_clients
+			.iter()
+			.find(|c| c.id == client_id)
+			.ok_or(AppErrorKind::InvalidClientID.into())
+	}
+
+	pub fn from_credentials(config: &ConfigFile, client_id: &str, client_secret: &str) -> Result<OIDCClient> {
+		let config_client = Self::from_id(config, client_id)?;
+
+		match (&config_client.client_type, &config_client.secret) {
+			(ClientType::Confidential, Some(secret)) if verify_secret(secret, client_secret) => Ok(config_client.clone()),
+			_ => Err(AppErrorKind::InvalidClientSecret.into()),
+		}
+	}
+
+	pub fn get_access_token_duration(&self, config: &ConfigFile) -> Duration {
+		self.access_token_duration.unwrap_or(config.session_duration)
+	}
+
+	pub fn get_id_token_duration(&self, config: &ConfigFile) -> Duration {
+		self.id_token_duration.unwrap_or(config.session_duration)
+	}
+
+	/// Checks that every requested scope is both supported and allowed for the client
+	pub fn check_scope(&self, scope: &str) -> Result<()> {
+		for requested in scope.split_whitespace() {
+			let supported = SUPPORTED_SCOPES.contains(&requested);
+			let allowed = self.allowed_scopes.is_empty() || self.allowed_scopes.iter().any(|s| s == requested);
+
+			if !supported || !allowed {
+				warn!("Client {} requested scope {} which is not allowed", self.id, requested);
+				return Err(AppErrorKind::InvalidScope.into());
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn requires_pkce(&self) -> bool {
+		self.client_type == ClientType::Public || self.require_pkce
+	}
+
+	pub fn check_code_challenge_method(&self, method: &str) -> Result<()> {
+		match method {
+			"S256" => Ok(()),
+			"plain" if self.allow_plain_pkce && self.client_type == ClientType::Confidential => Ok(()),
+			_ => {
+				warn!("Client {} used code_challenge_method {} which is not allowed", self.id, method);
+				Err(AppErrorKind::InvalidCodeChallengeMethod.into())
+			},
+		}
+	}
+
+	/// Checks the requested resource (if any) against the allowed resources of the client
+	pub fn check_resource(&self, resource: Option<&String>) -> Result<()> {
+		match resource {
+			Some(resource) if !self.resources.contains(resource) => {
+				warn!("Client {} requested resource {} which is not allowed", self.id, resource);
+				Err(AppErrorKind::InvalidTarget.into())
+			},
+			_ => Ok(()),
+		}
+	}
+
+	/// Whether this client is part of the audience of a token,
+	/// either as the client itself or as one of its resources
+	pub fn is_audience_of(&self, audience: &[String]) -> bool {
+		audience.contains(&self.id) ||
+		self.resources.iter().any(|r| audience.contains(r))
+	}
+
+	/// Matches a requested redirect URI against the registered ones.
+	/// Loopback redirects (RFC 8252 section 7.3) may use any port, as native apps bind a random one.
+	pub fn match_redirect_uri(&self, redirect_uri: &str) -> Option<RedirectUriMatch> {
+		if self.redirect_uris.iter().any(|r| r == redirect_uri) {
+			return if is_private_use_scheme(redirect_uri) {
+				Some(RedirectUriMatch::Native)
+			} else {
+				Some(RedirectUriMatch::Exact)
+			};
+		}
+
+		if let Ok(uri) = redirect_uri.parse::<Uri>() {
+			if is_loopback(&uri) {
+				let loopback_match = self.redirect_uris
+					.iter()
+					.filter_map(|r| r.parse::<Uri>().ok())
+					.any(|r|
+						is_loopback(&r) &&
+						r.host() == uri.host() &&
+						r.path_and_query() == uri.path_and_query());
+
+				if loopback_match {
+					return Some(RedirectUriMatch::Native);
+				}
+			}
+		}
+
+		if self.redirect_uri_patterns.iter().any(|p| matches_pattern(p, redirect_uri)) {
+			return Some(RedirectUriMatch::Pattern);
+		}
+
+		None
+	}
+
+	/// Whether a browser-based client at `origin` can call the OIDC endpoints (CORS)
+	pub fn allows_origin(&self, origin: &str) -> bool {
+		self.cors_origins.iter().any(|o| o == origin) ||
+		self.redirect_uris
+			.iter()
+			.filter_map(|r| r.parse::<Uri>().ok())
+			.filter(|r| matches!(r.scheme_str(), Some("http") | Some("https")))
+			.any(|r| r.authority().is_some_and(|a| format!("{}://{}", r.scheme_str().unwrap_or_default(), a) == origin))
+	}
+
 	pub async fn from_code(db: &reindeer::Db, code: &String, user: &User) -> Result<Option<OIDCClient>> {
 		let token = OIDCCodeToken::from_code(db, code).await?;
 		let auth_req = if let Some(metadata) = token.metadata {
@@ -34,8 +360,8 @@ impl OIDCClient {
 
 		if let Some(client) = config_client {
 			if let Some(redirect_url_enc) = &auth_req.redirect_uri {
-				let redirect_uri = urlencoding::decode(&redirect_url_enc)?;
-				if !client.redirect_uris.contains(&redirect_uri.to_string()) {
+				let redirect_uri = urlencoding::decode(redirect_url_enc)?;
+				if client.match_redirect_uri(&redirect_uri).is_none() {
 					warn!("Invalid redirect_uri: {} for client_id: {}", redirect_uri, auth_req.client_id);
 					return Ok(None);
 				}
@@ -45,3 +371,110 @@ impl OIDCClient {
 		Ok(config_client.cloned())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_match_redirect_uri() {
+		let client = OIDCClient {
+			id: "native".to_string(),
+			client_type: ClientType::Public,
+			secret: None,
+			require_pkce: false,
+			allow_plain_pkce: false,
+			name: None,
+			logo_uri: None,
+			client_uri: None,
+			policy_uri: None,
+			tos_uri: None,
+			send_login_hint: false,
+			backchannel_token_delivery_mode: None,
+			backchannel_client_notification_endpoint: None,
+			redirect_uris: vec![
+				"https://example.com/callback".to_string(),
+				"http://127.0.0.1/callback".to_string(),
+				"http://[::1]/callback".to_string(),
+				"com.example.app:/callback".to_string(),
+			],
+			redirect_uri_patterns: vec!["https://*.preview.example.com/callback".to_string()],
+			cors_origins: vec![],
+			realms: vec![],
+			token_exchange_targets: vec![],
+			resources: vec![],
+			dpop_bound_access_tokens: false,
+			allowed_scopes: vec![],
+			access_token_duration: None,
+			id_token_duration: None,
+			refresh_token_duration: None,
+		};
+
+		assert_eq!(client.match_redirect_uri("https://example.com/callback"), Some(RedirectUriMatch::Exact));
+		assert_eq!(client.match_redirect_uri("https://example.com/other"), None);
+
+		assert_eq!(client.match_redirect_uri("http://127.0.0.1:51234/callback"), Some(RedirectUriMatch::Native));
+		assert_eq!(client.match_redirect_uri("http://[::1]:8080/callback"), Some(RedirectUriMatch::Native));
+		assert_eq!(client.match_redirect_uri("http://127.0.0.1:51234/other"), None);
+		assert_eq!(client.match_redirect_uri("https://127.0.0.1:51234/callback"), None);
+		assert_eq!(client.match_redirect_uri("http://localhost:51234/callback"), None);
+
+		assert_eq!(client.match_redirect_uri("com.example.app:/callback"), Some(RedirectUriMatch::Native));
+		assert_eq!(client.match_redirect_uri("com.evil.app:/callback"), None);
+
+		assert_eq!(client.match_redirect_uri("https://pr-42.preview.example.com/callback"), Some(RedirectUriMatch::Pattern));
+		assert_eq!(client.match_redirect_uri("https://evil.com/.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://evil.com#.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://evil.com\\.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://evil.com%5C.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://evil.com%5c.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://a.b.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://user@pr-42.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://pr-42.preview.example.com:8443/callback"), None);
+		assert_eq!(client.match_redirect_uri("http://pr-42.preview.example.com/callback"), None);
+		assert_eq!(client.match_redirect_uri("https://pr-42.preview.example.com/callback/../evil"), None);
+		assert_eq!(client.match_redirect_uri("https://pr-42.preview.example.com/callback?next=evil"), None);
+
+		assert!(!RedirectUriMatch::Exact.requires_pkce());
+		assert!(RedirectUriMatch::Native.requires_pkce());
+		assert!(RedirectUriMatch::Pattern.requires_pkce());
+	}
+
+	#[test]
+	fn test_verify_secret() {
+		let (_, secret, argon2_hash) = generate_client().unwrap();
+		assert!(verify_secret(&argon2_hash, &secret));
+		assert!(!verify_secret(&argon2_hash, "wrong_secret"));
+
+		let bcrypt_hash = bcrypt::hash(&secret, 4).unwrap();
+		assert!(verify_secret(&bcrypt_hash, &secret));
+		assert!(!verify_secret(&bcrypt_hash, "wrong_secret"));
+
+		assert!(verify_secret("plain_secret", "plain_secret"));
+		assert!(!verify_secret("plain_secret", "plain_secre"));
+		assert!(!verify_secret("$argon2id$invalid", "$argon2id$invalid"));
+	}
+
+	#[actix_web::test]
+	async fn test_client_type() {
+		let config = CONFIG.read().await;
+
+		let confidential = OIDCClient::from_credentials(&config, "my_client", "my_secret").unwrap();
+		assert!(!confidential.requires_pkce());
+		assert!(confidential.check_code_challenge_method("S256").is_ok());
+		assert!(confidential.check_code_challenge_method("plain").is_err());
+		assert!(OIDCClient::from_credentials(&config, "my_client", "wrong_secret").is_err());
+
+		let public = OIDCClient::from_id(&config, "my_public_client").unwrap();
+		assert!(public.requires_pkce());
+		assert!(public.check_code_challenge_method("S256").is_ok());
+		assert!(OIDCClient::from_credentials(&config, "my_public_client", "").is_err());
+
+		let plain_public = OIDCClient { allow_plain_pkce: true, ..public.clone() };
+		assert!(plain_public.check_code_challenge_method("plain").is_err());
+
+		let plain_confidential = OIDCClient { allow_plain_pkce: true, ..confidential };
+		assert!(plain_confidential.check_code_challenge_method("plain").is_ok());
+	}
+}
@@ -0,0 +1,69 @@
+use chrono::{NaiveDateTime, Utc};
+use log::warn;
+use reindeer::{Db, Entity};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::token::{OIDCBearerToken, OIDCCodeToken, Token, TokenKindType};
+
+/// An `OIDCCodeToken` that has already been exchanged, kept around for the rest of its lifetime
+/// along with the bearer tokens that were issued from it, to detect replays (RFC 6749 section 4.1.2)
+#[derive(Entity, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[entity(name = "consumed_oidc_code", id = "code", version = 1)]
+pub struct ConsumedOIDCCode {
+	pub code: String,
+	pub expires_at: NaiveDateTime,
+	pub bearer_codes: Vec<String>,
+}
+
+impl ConsumedOIDCCode {
+	/// Returns the consumed code record, if the code has already been used before
+	pub fn from_code(db: &Db, code: &String) -> Result<Option<Self>> {
+		let now = Utc::now().naive_utc();
+		Self::filter_remove(|c| c.expires_at <= now, db)?;
+
+		Ok(Self::get(code, db)?)
+	}
+
+	/// Returns the consumed code that the token was issued from, directly or through refresh token rotation
+	pub fn from_issued_token(db: &Db, token_code: &str) -> Result<Option<Self>> {
+		let now = Utc::now().naive_utc();
+		Self::filter_remove(|c| c.expires_at <= now, db)?;
+
+		Ok(Self::get_with_filter(|c| c.bearer_codes.iter().any(|b| b == token_code), db)?.into_iter().next())
+	}
+
+	pub fn new(db: &Db, code_token: &OIDCCodeToken) -> Result<Self> {
+		let consumed = Self {
+			code: code_token.code.clone(),
+			expires_at: code_token.expires_at,
+			bearer_codes: Vec::new(),
+		};
+
+		consumed.save(db)?;
+
+		Ok(consumed)
+	}
+
+	/// Records a bearer or refresh token that was issued from the code
+	pub fn add_token<K: TokenKindType>(&mut self, db: &Db, token: &Token<K>) -> Result<()> {
+		self.bearer_codes.push(token.code.clone());
+		self.save(db)?;
+
+		Ok(())
+	}
+
+	/// Revokes all the bearer and refresh tokens that were issued from the code
+	pub async fn revoke(&self, db: &Db) -> Result<()> {
+		warn!("OIDC code replay detected, revoking {} bearer tokens", self.bearer_codes.len());
+
+		// All the token kinds share the same table, so this finds the refresh tokens as well
+		for bearer_code in self.bearer_codes.iter() {
+			if let Some(bearer) = OIDCBearerToken::get(bearer_code, db)? {
+				bearer.delete(db).await?;
+			}
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,102 @@
+use actix_cors::Cors;
+use actix_web::http::header;
+
+use crate::config::ConfigFile;
+use crate::oidc::dpop::DPOP_HEADER;
+use crate::CONFIG;
+
+/// The endpoints that browser-based clients (SPAs) call directly with `fetch`.
+/// The authorization endpoint is a top-level navigation and the introspection
+/// endpoint is meant for backends, so they don't need CORS.
+pub const CORS_PATHS: [&str; 4] = [
+	"/.well-known/openid-configuration",
+	"/oidc/jwks",
+	"/oidc/token",
+	"/oidc/userinfo",
+];
+
+pub fn is_allowed_origin(config: &ConfigFile, origin: &str, path: &str) -> bool {
+	CORS_PATHS.contains(&path) &&
+	config.oidc_clients.iter().any(|c| c.allows_origin(origin))
+}
+
+/// CORS middleware for the OIDC endpoints, allowing the origins of the clients
+/// (see `OIDCClient::allows_origin`). Requests of other origins are passed through
+/// without CORS headers, so the rest of the app behaves as before.
+pub fn cors() -> Cors {
+	Cors::default()
+		.allowed_origin_fn(|origin, head| {
+			let Ok(origin) = origin.to_str() else {
+				return false;
+			};
+
+			// The origin check can't be async - if the config is being reloaded right now, deny
+			let Ok(config) = CONFIG.try_read() else {
+				log::warn!("Config is locked, denying CORS request from {}", origin);
+				return false;
+			};
+
+			is_allowed_origin(&config, origin, head.uri.path())
+		})
+		.allowed_methods(["GET", "POST"])
+		.allowed_headers(["Authorization", "Content-Type", DPOP_HEADER])
+		.expose_headers([header::WWW_AUTHENTICATE])
+		.block_on_origin_mismatch(false)
+		.max_age(3600)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::oidc::handle_jwks;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, web, App};
+
+	#[actix_web::test]
+	async fn test_cors() {
+		let keypair = jwt_simple::algorithms::RS256KeyPair::generate(2048).unwrap().with_key_id("default");
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(keypair))
+				.service(handle_jwks::jwks)
+				.wrap(cors())
+		)
+		.await;
+
+		// Preflight of a registered redirect URI origin
+		let req = actix_test::TestRequest::default()
+			.method(actix_web::http::Method::OPTIONS)
+			.uri("/oidc/jwks")
+			.insert_header((header::ORIGIN, "https://openidconnect.net"))
+			.insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+			.insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, DPOP_HEADER))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://openidconnect.net");
+
+		let req = actix_test::TestRequest::get()
+			.uri("/oidc/jwks")
+			.insert_header((header::ORIGIN, "https://openidconnect.net"))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert!(resp.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+		// Unknown origins get no CORS headers
+		let req = actix_test::TestRequest::get()
+			.uri("/oidc/jwks")
+			.insert_header((header::ORIGIN, "https://evil.example.com"))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert!(!resp.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+		let config = CONFIG.read().await;
+		assert!(is_allowed_origin(&config, "https://openidconnect.net", "/oidc/token"));
+		assert!(is_allowed_origin(&config, "https://spa.example.com", "/oidc/userinfo"));
+		assert!(!is_allowed_origin(&config, "https://openidconnect.net", "/oidc/introspect"));
+		assert!(!is_allowed_origin(&config, "https://evil.example.com", "/oidc/token"));
+	}
+}
@@ -0,0 +1,306 @@
+use actix_web::HttpRequest;
+use chrono::{NaiveDateTime, Utc};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder as _, Encoder as _};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use reindeer::{Db, Entity};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{AppErrorKind, Result};
+use crate::CONFIG;
+
+pub const DPOP_HEADER: &str = "DPoP";
+pub const DPOP_TOKEN_TYPE: &str = "DPoP";
+/// How far in the past or the future the `iat` of a proof can be
+pub const DPOP_PROOF_MAX_AGE: i64 = 300;
+/// JWK members that only exist in private or symmetric keys, which must never be part of a proof
+pub const DPOP_PRIVATE_JWK_MEMBERS: [&str; 8] = ["d", "p", "q", "dp", "dq", "qi", "oth", "k"];
+
+/// Serializes the replay check of the `jti`s so that two copies of the same proof can't both pass
+static DPOP_JTI_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// The public key that the client embeds in the header of the proof
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DPoPJwk {
+	pub kty: String,
+	pub crv: Option<String>,
+	pub x: Option<String>,
+	pub y: Option<String>,
+	pub n: Option<String>,
+	pub e: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DPoPHeader {
+	pub typ: String,
+	pub alg: String,
+	pub jwk: DPoPJwk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DPoPClaims {
+	pub jti: String,
+	pub htm: String,
+	pub htu: String,
+	pub iat: i64,
+	pub ath: Option<String>,
+}
+
+/// The confirmation claim (`cnf`) of a token bound to a DPoP key
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Confirmation {
+	pub jkt: String,
+}
+
+/// The `jti`s of the proofs that have already been used, to detect replays
+#[derive(Entity, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[entity(name = "dpop_jti", id = "jti", version = 1)]
+pub struct DPoPProofJti {
+	pub jti: String,
+	pub expires_at: NaiveDateTime,
+}
+
+fn decode(value: &Option<String>) -> Result<Vec<u8>> {
+	let value = value.as_ref().ok_or(AppErrorKind::InvalidDPoPProof)?;
+	Base64UrlSafeNoPadding::decode_to_vec(value, None).map_err(|_| AppErrorKind::InvalidDPoPProof.into())
+}
+
+impl DPoPJwk {
+	/// The JWK SHA-256 thumbprint as described in RFC 7638
+	pub fn thumbprint(&self) -> Result<String> {
+		let canonical = match self.kty.as_str() {
+			"EC" => format!(
+				r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#,
+				self.crv.as_ref().ok_or(AppErrorKind::InvalidDPoPProof)?,
+				self.x.as_ref().ok_or(AppErrorKind::InvalidDPoPProof)?,
+				self.y.as_ref().ok_or(AppErrorKind::InvalidDPoPProof)?),
+			"RSA" => format!(
+				r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#,
+				self.e.as_ref().ok_or(AppErrorKind::InvalidDPoPProof)?,
+				self.n.as_ref().ok_or(AppErrorKind::InvalidDPoPProof)?),
+			_ => return Err(AppErrorKind::InvalidDPoPProof.into()),
+		};
+
+		Ok(Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(canonical.as_bytes()))?)
+	}
+
+	pub fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> Result<bool> {
+		let invalid = |_| AppErrorKind::InvalidDPoPProof;
+
+		match (alg, self.kty.as_str(), self.crv.as_deref()) {
+			("ES256", "EC", Some("P-256")) => {
+				if signature.len() != 64 {
+					return Ok(false);
+				}
+
+				let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(invalid)?;
+				let x = BigNum::from_slice(&decode(&self.x)?).map_err(invalid)?;
+				let y = BigNum::from_slice(&decode(&self.y)?).map_err(invalid)?;
+				let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y).map_err(invalid)?;
+
+				// JWS uses the raw r || s form of the signature instead of DER
+				let r = BigNum::from_slice(&signature[..32]).map_err(invalid)?;
+				let s = BigNum::from_slice(&signature[32..]).map_err(invalid)?;
+				let sig = EcdsaSig::from_private_components(r, s).map_err(invalid)?;
+
+				Ok(sig.verify(&Sha256::digest(message), &key).unwrap_or(false))
+			},
+			("RS256", "RSA", _) => {
+				let n = BigNum::from_slice(&decode(&self.n)?).map_err(invalid)?;
+				let e = BigNum::from_slice(&decode(&self.e)?).map_err(invalid)?;
+				let key = PKey::from_rsa(Rsa::from_public_components(n, e).map_err(invalid)?).map_err(invalid)?;
+
+				let mut verifier = Verifier::new(MessageDigest::sha256(), &key).map_err(invalid)?;
+				verifier.update(message).map_err(invalid)?;
+				Ok(verifier.verify(signature).unwrap_or(false))
+			},
+			_ => Err(AppErrorKind::InvalidDPoPProof.into()),
+		}
+	}
+}
+
+/// The `ath` claim of a proof sent along an access token
+pub fn get_access_token_hash(access_token: &str) -> Result<String> {
+	Ok(Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(access_token.as_bytes()))?)
+}
+
+/// Verifies a DPoP proof (RFC 9449 section 4.3) and returns the thumbprint of its key
+pub async fn verify_proof(db: &Db, proof: &str, method: &str, url: &str, access_token: Option<&str>) -> Result<String> {
+	let parts = proof.split('.').collect::<Vec<&str>>();
+	if parts.len() != 3 {
+		return Err(AppErrorKind::InvalidDPoPProof.into());
+	}
+
+	let header_bytes = decode(&Some(parts[0].to_string()))?;
+	let claims_bytes = decode(&Some(parts[1].to_string()))?;
+	let signature = decode(&Some(parts[2].to_string()))?;
+	let header = serde_json::from_slice::<DPoPHeader>(&header_bytes).map_err(|_| AppErrorKind::InvalidDPoPProof)?;
+	let claims = serde_json::from_slice::<DPoPClaims>(&claims_bytes).map_err(|_| AppErrorKind::InvalidDPoPProof)?;
+
+	let raw_header = serde_json::from_slice::<serde_json::Value>(&header_bytes).map_err(|_| AppErrorKind::InvalidDPoPProof)?;
+	if DPOP_PRIVATE_JWK_MEMBERS.iter().any(|m| raw_header["jwk"].get(m).is_some()) {
+		return Err(AppErrorKind::InvalidDPoPProof.into());
+	}
+
+	if header.typ != "dpop+jwt" {
+		return Err(AppErrorKind::InvalidDPoPProof.into());
+	}
+
+	let signed = format!("{}.{}", parts[0], parts[1]);
+	if !header.jwk.verify(&header.alg, signed.as_bytes(), &signature)? {
+		return Err(AppErrorKind::InvalidDPoPProof.into());
+	}
+
+	// The query and fragment are not part of the htu
+	let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+	if !claims.htm.eq_ignore_ascii_case(method) || htu != url {
+		log::warn!("DPoP proof for {} {} used for {} {}", claims.htm, htu, method, url);
+		return Err(AppErrorKind::InvalidDPoPProof.into());
+	}
+
+	let now = Utc::now();
+	if (now.timestamp() - claims.iat).abs() > DPOP_PROOF_MAX_AGE {
+		return Err(AppErrorKind::InvalidDPoPProof.into());
+	}
+
+	match (access_token, &claims.ath) {
+		(Some(access_token), Some(ath)) if &get_access_token_hash(access_token)? == ath => {},
+		(None, _) => {},
+		_ => return Err(AppErrorKind::InvalidDPoPProof.into()),
+	}
+
+	let now_naive = now.naive_utc();
+	let _jti_lock = DPOP_JTI_LOCK.lock().await;
+	DPoPProofJti::filter_remove(|j| j.expires_at <= now_naive, db)?;
+	if DPoPProofJti::get(&claims.jti, db)?.is_some() {
+		return Err(AppErrorKind::DPoPProofReplayed.into());
+	}
+
+	DPoPProofJti {
+		jti: claims.jti.clone(),
+		expires_at: now_naive + chrono::Duration::try_seconds(DPOP_PROOF_MAX_AGE * 2).unwrap_or_default(),
+	}.save(db)?;
+
+	header.jwk.thumbprint()
+}
+
+/// Verifies the DPoP proof of the request, if any, and returns the thumbprint of its key
+pub async fn proof_from_request(db: &Db, req: &HttpRequest, access_token: Option<&str>) -> Result<Option<String>> {
+	let Some(proof) = req.headers().get(DPOP_HEADER) else {
+		return Ok(None);
+	};
+
+	let url = format!("{}{}", CONFIG.read().await.url_from_request(req), req.path());
+	let jkt = verify_proof(db, proof.to_str().map_err(|_| AppErrorKind::InvalidDPoPProof)?, req.method().as_str(), &url, access_token).await?;
+
+	Ok(Some(jkt))
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+	use crate::utils::tests::*;
+	use crate::utils::random_string;
+
+	use openssl::bn::BigNumContext;
+	use openssl::pkey::Private;
+	use openssl::sign::Signer;
+
+	pub fn create_key() -> EcKey<Private> {
+		let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+		EcKey::generate(&group).unwrap()
+	}
+
+	/// The public JWK of the given P-256 key
+	pub fn create_jwk(key: &EcKey<Private>) -> DPoPJwk {
+		let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+		let mut ctx = BigNumContext::new().unwrap();
+		let mut x = BigNum::new().unwrap();
+		let mut y = BigNum::new().unwrap();
+		key.public_key().affine_coordinates(&group, &mut x, &mut y, &mut ctx).unwrap();
+
+		DPoPJwk {
+			kty: "EC".to_string(),
+			crv: Some("P-256".to_string()),
+			x: Some(Base64UrlSafeNoPadding::encode_to_string(x.to_vec_padded(32).unwrap()).unwrap()),
+			y: Some(Base64UrlSafeNoPadding::encode_to_string(y.to_vec_padded(32).unwrap()).unwrap()),
+			n: None,
+			e: None,
+		}
+	}
+
+	/// Creates a DPoP proof with the given P-256 key, returning the proof and the thumbprint of the key
+	pub fn create_proof(key: &EcKey<Private>, method: &str, url: &str, access_token: Option<&str>) -> (String, String) {
+		let jwk = create_jwk(key);
+		let header = serde_json::json!({ "typ": "dpop+jwt", "alg": "ES256", "jwk": jwk });
+		(sign_proof(key, &header, method, url, access_token), jwk.thumbprint().unwrap())
+	}
+
+	/// Signs a DPoP proof with the given P-256 key and (possibly invalid) header
+	pub fn sign_proof(key: &EcKey<Private>, header: &serde_json::Value, method: &str, url: &str, access_token: Option<&str>) -> String {
+		let claims = DPoPClaims {
+			jti: random_string(),
+			htm: method.to_string(),
+			htu: url.to_string(),
+			iat: Utc::now().timestamp(),
+			ath: access_token.map(|t| get_access_token_hash(t).unwrap()),
+		};
+
+		let signed = format!("{}.{}",
+			Base64UrlSafeNoPadding::encode_to_string(header.to_string()).unwrap(),
+			Base64UrlSafeNoPadding::encode_to_string(serde_json::to_string(&claims).unwrap()).unwrap());
+
+		let pkey = PKey::from_ec_key(key.clone()).unwrap();
+		let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+		signer.update(signed.as_bytes()).unwrap();
+		let der = EcdsaSig::from_der(&signer.sign_to_vec().unwrap()).unwrap();
+		let mut signature = der.r().to_vec_padded(32).unwrap();
+		signature.extend(der.s().to_vec_padded(32).unwrap());
+
+		format!("{}.{}", signed, Base64UrlSafeNoPadding::encode_to_string(signature).unwrap())
+	}
+
+	#[actix_web::test]
+	async fn test_dpop_proof() {
+		let db = &db_connect().await;
+		let url = "http://localhost:8080/oidc/token";
+
+		let (proof, jkt) = create_proof(&create_key(), "POST", url, None);
+		assert_eq!(verify_proof(db, &proof, "POST", url, None).await.unwrap(), jkt);
+
+		// Replayed proof
+		assert!(verify_proof(db, &proof, "POST", url, None).await.is_err());
+
+		// Proof for a different endpoint
+		let (proof, _) = create_proof(&create_key(), "POST", url, None);
+		assert!(verify_proof(db, &proof, "GET", "http://localhost:8080/oidc/userinfo", None).await.is_err());
+
+		// Proof bound to a different access token
+		let (proof, _) = create_proof(&create_key(), "GET", url, Some("some_token"));
+		assert!(verify_proof(db, &proof, "GET", url, Some("other_token")).await.is_err());
+
+		// Tampered proof
+		let (proof, _) = create_proof(&create_key(), "POST", url, None);
+		let tampered = format!("{}A", proof);
+		assert!(verify_proof(db, &tampered, "POST", url, None).await.is_err());
+
+		// Proof that leaks the private part of its key
+		let key = create_key();
+		let mut jwk = serde_json::to_value(create_jwk(&key)).unwrap();
+		jwk["d"] = Base64UrlSafeNoPadding::encode_to_string(key.private_key().to_vec_padded(32).unwrap()).unwrap().into();
+		let proof = sign_proof(&key, &serde_json::json!({ "typ": "dpop+jwt", "alg": "ES256", "jwk": jwk }), "POST", url, None);
+		assert!(verify_proof(db, &proof, "POST", url, None).await.is_err());
+
+		// Concurrent copies of the same proof
+		let (proof, _) = create_proof(&create_key(), "POST", url, None);
+		let results = futures::future::join_all((0..8).map(|_| verify_proof(db, &proof, "POST", url, None))).await;
+		assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+	}
+}
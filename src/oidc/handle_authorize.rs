@@ -7,16 +7,22 @@ use actix_web::HttpRequest;
 use actix_web::{get, post, web, HttpResponse, Responder};
 use log::info;
 use jwt_simple::prelude::*;
+use jwt_simple::reexports::ct_codecs::Base64UrlSafeNoPadding;
+use sha2::{Digest, Sha256};
 
 use crate::error::Error;
 use crate::error::{AppErrorKind, Response};
+use crate::handle_login_action::{login_action_page, send_magic_link};
 use crate::token::{OIDCCodeToken, SessionToken};
-use crate::oidc::handle_token::JWTData;
+use crate::oidc::claims::ClaimsRequest;
+use crate::oidc::client::OIDCClient;
+use crate::oidc::handle_check_session::{get_browser_state, get_session_state};
+use crate::oidc::handle_token::{JWTData, JWTType};
 use crate::user::User;
-use crate::{AUTHORIZATION_COOKIE, CONFIG};
+use crate::{SmtpTransport, AUTHORIZATION_COOKIE, CONFIG};
 use crate::utils::get_partial;
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct AuthorizeRequest {
 	pub scope: String,
 	pub response_type: String,
@@ -25,6 +31,18 @@ pub struct AuthorizeRequest {
 	pub state: Option<String>,
 	pub code_challenge: Option<String>,
 	pub code_challenge_method: Option<String>,
+	pub resource: Option<String>,
+	pub login_hint: Option<String>,
+	/// The `claims` parameter, kept as the JSON string so that it survives the query string encoding
+	pub claims: Option<String>,
+}
+
+/// The origin of a redirect URI, or just the scheme for the private-use URI schemes of native apps
+fn get_origin(redirect_url: &str) -> Option<String> {
+	match redirect_url.parse::<Uri>() {
+		Ok(uri) if uri.authority().is_some() => Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?)),
+		_ => redirect_url.split_once(':').map(|(scheme, _)| format!("{}:", scheme)),
+	}
 }
 
 impl AuthorizeRequest {
@@ -33,9 +51,40 @@ impl AuthorizeRequest {
 		OIDCCodeToken::new(db, user, Some(bound_to), Some(self_string)).await
 	}
 
-	pub async fn get_redirect_url(&self, code: &str, user: &User) -> Option<String> {
+	/// The method defaults to `plain` when omitted (RFC 7636 section 4.3)
+	pub fn get_code_challenge_method(&self) -> &str {
+		self.code_challenge_method.as_deref().unwrap_or("plain")
+	}
+
+	pub fn verify_code_verifier(&self, code_verifier: &str) -> Result<(), Error> {
+		let code_challenge = self.code_challenge.as_ref().ok_or(AppErrorKind::InvalidCodeVerifier)?;
+
+		let generated_code_challenge = match self.get_code_challenge_method() {
+			"S256" => {
+				let mut hasher = Sha256::new();
+				hasher.update(code_verifier.as_bytes());
+				Base64UrlSafeNoPadding::encode_to_string(hasher.finalize())?
+			},
+			"plain" => code_verifier.to_string(),
+			_ => return Err(AppErrorKind::InvalidCodeChallengeMethod.into()),
+		};
+
+		if &generated_code_challenge != code_challenge {
+			return Err(AppErrorKind::InvalidCodeVerifier.into());
+		}
+
+		Ok(())
+	}
+
+	pub fn get_claims(&self) -> Result<ClaimsRequest, Error> {
+		self.claims
+			.as_ref()
+			.map_or(Ok(ClaimsRequest::default()), |claims| ClaimsRequest::parse(claims))
+	}
+
+	pub async fn get_redirect_url(&self, code: &str, user: &User, browser_state: &str) -> Option<String> {
 		let redirect_url = if let Some(redirect_url_enc) = &self.redirect_uri {
-			urlencoding::decode(&redirect_url_enc).ok()?.to_string()
+			urlencoding::decode(redirect_url_enc).ok()?.to_string()
 		} else {
 			return None;
 		};
@@ -46,32 +95,40 @@ impl AuthorizeRequest {
 			.find(|c|
 				user.has_any_realm(&c.realms) &&
 				c.id == self.client_id &&
-				c.redirect_uris.contains(&redirect_url));
+				c.match_redirect_uri(&redirect_url).is_some());
 
 		if config_client.is_none() {
 			log::warn!("Invalid redirect_uri: {} for client_id: {}", redirect_url, self.client_id);
 			return None;
 		}
 
-		Some(format!("{}?code={}&state={}",
+		let origin = get_origin(&redirect_url)?;
+		let session_state = get_session_state(&self.client_id, &origin, browser_state);
+
+		Some(format!("{}?code={}&state={}&session_state={}",
 			redirect_url,
 			code,
-			self.state.clone().unwrap_or_default()))
+			self.state.clone().unwrap_or_default(),
+			session_state))
 	}
 
-	pub async fn generate_id_token(&self, user: &User, url: String, keypair: &RS256KeyPair) -> Result<String, Error> {
+	pub async fn generate_id_token(&self, user: &User, url: String, keypair: &RS256KeyPair, duration: chrono::Duration) -> Result<String, Error> {
+		let claims = self.get_claims()?;
+		claims.check_subject(user)?;
+
 		let jwt_data = JWTData {
 			user: user.email.clone(),
-			client_id: self.client_id.clone(),
-			..JWTData::new(url).await
+			audience: vec![self.client_id.clone()],
+			token_type: Some(JWTType::ID),
+			claims: claims.get_id_token_claims(user),
+			..JWTData::new(url)
 		};
 		println!("JWT Data: {:?}", jwt_data);
 
-		let config = CONFIG.read().await;
 		let claims = Claims::with_custom_claims(
 			jwt_data,
 			Duration::from_millis(
-				config.session_duration
+				duration
 				.num_milliseconds()
 				.try_into()
 				.map_err(|_| AppErrorKind::InvalidDuration)?));
@@ -95,23 +152,62 @@ impl TryFrom<&AuthorizeRequest> for String {
 	}
 }
 
-async fn authorize(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, auth_req: AuthorizeRequest) -> Response {
+async fn authorize(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, mailer: web::Data<Option<SmtpTransport>>, http_client: web::Data<Option<reqwest::Client>>, auth_req: AuthorizeRequest) -> Response {
 	info!("Beginning OIDC flow for {}", auth_req.client_id);
 
-	if let Some(code_challenge_method) = auth_req.code_challenge_method.as_ref() {
-		// TODO: Support plain
-		if code_challenge_method != "S256" {
-			return Err(AppErrorKind::InvalidCodeChallengeMethod.into());
+	let client = {
+		let config = CONFIG.read().await;
+		let client = OIDCClient::from_id(&config, &auth_req.client_id)?;
+		client.check_resource(auth_req.resource.as_ref())?;
+
+		let mut requires_pkce = client.requires_pkce();
+		if let Some(redirect_url_enc) = &auth_req.redirect_uri {
+			let redirect_url = urlencoding::decode(redirect_url_enc)?;
+			let redirect_match = client.match_redirect_uri(&redirect_url).ok_or(AppErrorKind::InvalidRedirectUri)?;
+			requires_pkce |= redirect_match.requires_pkce();
+
+			// The redirect_uri is trusted at this point, so the error goes back to the client (RFC 6749 section 4.1.2.1)
+			if client.check_scope(&auth_req.scope).is_err() {
+				let error_url = format!("{}{}error=invalid_scope&state={}",
+					redirect_url,
+					if redirect_url.contains('?') { '&' } else { '?' },
+					urlencoding::encode(&auth_req.state.clone().unwrap_or_default()));
+				return Ok(HttpResponse::Found()
+					.append_header(("Location", error_url))
+					.finish());
+			}
 		}
 
-		if auth_req.code_challenge.is_none() {
+		client.check_scope(&auth_req.scope)?;
+		auth_req.get_claims()?;
+
+		if auth_req.code_challenge.is_some() {
+			client.check_code_challenge_method(auth_req.get_code_challenge_method())?;
+		} else if auth_req.code_challenge_method.is_some() {
 			return Err(AppErrorKind::InvalidCodeChallengeMethod.into());
+		} else if requires_pkce {
+			return Err(AppErrorKind::PKCERequired.into());
 		}
-	}
+
+		client.clone()
+	};
 
 	session.insert(AUTHORIZATION_COOKIE, auth_req.clone())?;
 
 	let Ok(token) = SessionToken::from_session(&db, &session).await else {
+		// Clients that opted in skip the login form, the link is sent to the hinted email right away
+		if client.send_login_hint {
+			if let Some(login_hint) = &auth_req.login_hint {
+				if let Some(user) = User::from_config(login_hint).await {
+					info!("Sending magic link to the login_hint of {}", auth_req.client_id);
+					send_magic_link(&req, &db, &mailer, &http_client, &user).await?;
+				}
+
+				// Same page for unknown emails, to avoid leaking valid ones
+				return login_action_page();
+			}
+		}
+
 		let config = CONFIG.read().await;
 		let base_url = config.url_from_request(&req);
 		let target_url = format!("{}/login?{}", base_url, serde_qs::to_string(&auth_req)?);
@@ -120,22 +216,43 @@ async fn authorize(req: HttpRequest, session: Session, db: web::Data<reindeer::D
 			.finish())
 	};
 
-	let oidc_session = auth_req.generate_session_code(&db, token.user.clone(), token.code).await?;
+	auth_req.get_claims()?.check_subject(&token.user)?;
+	let oidc_session = auth_req.generate_session_code(&db, token.user.clone(), token.code.clone()).await?;
 	println!("OIDC Session: {:?}", oidc_session);
 
 	// TODO: Check the state with the cookie for CSRF
-	let redirect_url = auth_req.get_redirect_url(&oidc_session.code, &oidc_session.user).await.ok_or(AppErrorKind::InvalidRedirectUri)?;
-	let redirect_url_uri = redirect_url.parse::<Uri>()?;
-	let redirect_url_scheme = redirect_url_uri.scheme_str().ok_or(AppErrorKind::InvalidRedirectUri)?;
-	let redirect_url_authority = redirect_url_uri.authority().ok_or(AppErrorKind::InvalidRedirectUri)?;
-	let redirect_url_str = format!("{}://{}", redirect_url_scheme, redirect_url_authority);
+	let browser_state = get_browser_state(&token.code);
+	let redirect_url = auth_req.get_redirect_url(&oidc_session.code, &oidc_session.user, &browser_state).await.ok_or(AppErrorKind::InvalidRedirectUri)?;
+	let redirect_url_str = get_origin(&redirect_url).ok_or(AppErrorKind::InvalidRedirectUri)?;
 
 	let mut authorize_data = BTreeMap::new();
 	authorize_data.insert("name", token.user.name.clone());
 	authorize_data.insert("username", token.user.username.clone());
 	authorize_data.insert("email", token.user.email.clone());
-	authorize_data.insert("client", redirect_url_str.clone());
+	authorize_data.insert("client", client.name.clone().unwrap_or(redirect_url_str.clone()));
+	authorize_data.insert("client_origin", redirect_url_str.clone());
 	authorize_data.insert("link", redirect_url.clone());
+
+	for (key, value) in [
+		("logo_uri", &client.logo_uri),
+		("client_uri", &client.client_uri),
+		("policy_uri", &client.policy_uri),
+		("tos_uri", &client.tos_uri),
+	] {
+		if let Some(value) = value {
+			authorize_data.insert(key, value.clone());
+		}
+	}
+
+	// The template can only check for the presence of a key, e.g. `{{#if scope_email}}`
+	for scope in auth_req.scope.split_whitespace() {
+		match scope {
+			"profile" => authorize_data.insert("scope_profile", scope.to_string()),
+			"email" => authorize_data.insert("scope_email", scope.to_string()),
+			"offline_access" => authorize_data.insert("scope_offline_access", scope.to_string()),
+			_ => None,
+		};
+	}
 	let authorize_page = get_partial("authorize", authorize_data)?;
 
 	Ok(HttpResponse::Ok()
@@ -146,11 +263,11 @@ async fn authorize(req: HttpRequest, session: Session, db: web::Data<reindeer::D
 }
 
 #[get("/oidc/authorize")]
-pub async fn authorize_get(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, data: web::Query<AuthorizeRequest>) -> impl Responder {
-	authorize(req, session, db, data.into_inner()).await
+pub async fn authorize_get(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, mailer: web::Data<Option<SmtpTransport>>, http_client: web::Data<Option<reqwest::Client>>, data: web::Query<AuthorizeRequest>) -> impl Responder {
+	authorize(req, session, db, mailer, http_client, data.into_inner()).await
 }
 
 #[post("/oidc/authorize")]
-pub async fn authorize_post(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, data: web::Form<AuthorizeRequest>) -> impl Responder {
-	authorize(req, session, db, data.into_inner()).await
+pub async fn authorize_post(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, mailer: web::Data<Option<SmtpTransport>>, http_client: web::Data<Option<reqwest::Client>>, data: web::Form<AuthorizeRequest>) -> impl Responder {
+	authorize(req, session, db, mailer, http_client, data.into_inner()).await
 }
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::ContentType;
+use actix_web::{get, HttpResponse};
+use sha2::{Digest, Sha256};
+
+use crate::error::Response;
+use crate::utils::{get_partial, random_string};
+use crate::BROWSER_STATE_COOKIE;
+
+/// The browser state is the value of the cookie that the check session iframe
+/// reads to figure out if the session of the user has changed.
+/// It's derived from the session code so that it changes on every login
+/// without the need to store it anywhere, while not leaking the session code.
+pub fn get_browser_state(session_code: &str) -> String {
+	let mut hasher = Sha256::new();
+	hasher.update(session_code.as_bytes());
+	hex::encode(hasher.finalize())
+}
+
+/// The `session_state` as described in OIDC Session Management 1.0, section 3:
+/// `sha256(client_id + " " + origin + " " + browser_state + " " + salt) + "." + salt`
+pub fn get_session_state(client_id: &str, origin: &str, browser_state: &str) -> String {
+	let salt = random_string();
+	let mut hasher = Sha256::new();
+	hasher.update(format!("{} {} {} {}", client_id, origin, browser_state, salt).as_bytes());
+	format!("{}.{}", hex::encode(hasher.finalize()), salt)
+}
+
+/// The cookie has to be readable by the JS of the iframe (so not HttpOnly)
+/// and sent to the iframe while it's embedded in the client (so SameSite=None)
+pub fn get_browser_state_cookie(browser_state: String) -> Cookie<'static> {
+	let mut cookie = Cookie::build(BROWSER_STATE_COOKIE, browser_state)
+		.path("/")
+		.http_only(false)
+		.secure(true)
+		.same_site(SameSite::None)
+		.finish();
+
+	if cookie.value().is_empty() {
+		cookie.make_removal();
+	}
+
+	cookie
+}
+
+#[get("/oidc/check_session")]
+pub async fn check_session() -> Response {
+	let mut check_session_data = BTreeMap::new();
+	check_session_data.insert("cookie", BROWSER_STATE_COOKIE.to_string());
+	let check_session_page = get_partial("check_session", check_session_data)?;
+
+	Ok(HttpResponse::Ok()
+		.content_type(ContentType::html())
+		.body(check_session_page))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::oidc::handle_authorize;
+	use crate::token::MagicLinkToken;
+	use crate::utils::tests::*;
+
+	use actix_session::storage::CookieSessionStore;
+	use actix_session::SessionMiddleware;
+	use actix_web::cookie::Key;
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, web, App};
+
+	#[actix_web::test]
+	async fn test_check_session() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(None::<crate::SmtpTransport>))
+				.app_data(web::Data::new(None::<reqwest::Client>))
+				.service(crate::handle_login_link::login_link)
+				.service(crate::handle_logout::logout)
+				.service(handle_authorize::authorize_get)
+				.service(check_session)
+				.wrap(SessionMiddleware::builder(CookieSessionStore::default(), Key::from(&[0; 64])).build())
+		)
+		.await;
+
+		let client_id = "my_client";
+		let req = actix_test::TestRequest::get()
+			.uri(&format!(
+				"/oidc/authorize?client_id={}&redirect_uri={}&scope=openid&response_type=code&state=state",
+				client_id,
+				urlencoding::encode("https://openidconnect.net/callback")))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		let session_cookie = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
+
+		// The login sets the browser state cookie that the iframe reads
+		let token = MagicLinkToken::new(db, user, None, None).await.unwrap();
+		let req = actix_test::TestRequest::get()
+			.uri(&format!("/login/{}", token.code))
+			.cookie(session_cookie)
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		let browser_state = resp.response().cookies().find(|c| c.name() == BROWSER_STATE_COOKIE).unwrap().into_owned();
+		assert!(!browser_state.value().is_empty());
+		assert_eq!(browser_state.same_site(), Some(SameSite::None));
+		let session_cookie = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
+
+		// The session_state of the redirect is what the iframe computes from the cookie and the origin of the client
+		let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+		let session_state = location.split("&session_state=").nth(1).unwrap();
+		let (hash, salt) = session_state.split_once('.').unwrap();
+		let expected = Sha256::digest(format!("{} {} {} {}", client_id, "https://openidconnect.net", browser_state.value(), salt).as_bytes());
+		assert_eq!(hash, hex::encode(expected));
+
+		let page = actix_test::call_and_read_body(&mut app, actix_test::TestRequest::get().uri("/oidc/check_session").to_request()).await;
+		let page = std::str::from_utf8(&page).unwrap();
+		assert!(page.contains(&format!(r#"c.startsWith("{}=")"#, BROWSER_STATE_COOKIE)));
+
+		// The logout removes it, so the iframe reports a change
+		let req = actix_test::TestRequest::get()
+			.uri("/logout")
+			.cookie(session_cookie)
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		let removed = resp.response().cookies().find(|c| c.name() == BROWSER_STATE_COOKIE).unwrap().into_owned();
+		assert_eq!(removed.value(), "");
+		assert_eq!(removed.max_age(), Some(actix_web::cookie::time::Duration::ZERO));
+	}
+}
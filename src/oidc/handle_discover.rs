@@ -1,6 +1,12 @@
 use serde::{Serialize, Serializer};
 use actix_web::{get, HttpRequest, HttpResponse, Responder};
 
+use crate::oidc::ciba::CIBA_GRANT_TYPE;
+use crate::oidc::claims::SUPPORTED_CLAIMS;
+use crate::oidc::handle_token::AUTHORIZATION_CODE_GRANT_TYPE;
+use crate::oidc::refresh_token::REFRESH_TOKEN_GRANT_TYPE;
+use crate::oidc::token_exchange::TOKEN_EXCHANGE_GRANT_TYPE;
+use crate::oidc::SUPPORTED_SCOPES;
 use crate::CONFIG;
 
 fn serialize_vec_with_space<S: Serializer>(vec: &Vec<&str>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
@@ -15,17 +21,24 @@ pub struct Discovery<'a> {
 	pub authorization_endpoint: String,
 	pub token_endpoint: String,
 	pub userinfo_endpoint: String,
+	pub introspection_endpoint: String,
 	pub end_session_endpoint: String,
-	// TODO: check_session_iframe
+	pub check_session_iframe: String,
 	pub jwks_uri: String,
+	pub backchannel_authentication_endpoint: String,
 
 	#[serde(serialize_with = "serialize_vec_with_space")]
 	pub scopes_supported: Vec<&'a str>,
 	pub response_types_supported: Vec<&'a str>,
+	pub grant_types_supported: Vec<&'a str>,
 	pub id_token_signing_alg_values_supported: Vec<&'a str>,
 	pub userinfo_signing_alg_values_supported: Vec<&'a str>,
+	pub dpop_signing_alg_values_supported: Vec<&'a str>,
 	// pub token_endpoint_auth_methods_supported: Vec<&'a str>,
 	pub claims_supported: Vec<&'a str>,
+	pub claims_parameter_supported: bool,
+	pub backchannel_token_delivery_modes_supported: Vec<&'a str>,
+	pub backchannel_user_code_parameter_supported: bool,
 
 	pub subject_types_supported: Vec<&'a str>,
 }
@@ -38,14 +51,22 @@ impl<'a> Discovery<'a> {
 			authorization_endpoint: format!("{}/oidc/authorize", external_url),
 			token_endpoint: format!("{}/oidc/token", base),
 			userinfo_endpoint: format!("{}/oidc/userinfo", base),
+			introspection_endpoint: format!("{}/oidc/introspect", base),
 			end_session_endpoint: format!("{}/logout", external_url),
+			check_session_iframe: format!("{}/oidc/check_session", external_url),
 			jwks_uri: format!("{}/oidc/jwks", base),
+			backchannel_authentication_endpoint: format!("{}/oidc/bc-authorize", base),
 
-			scopes_supported: vec!["openid", "profile", "email"],
+			scopes_supported: SUPPORTED_SCOPES.to_vec(),
 			response_types_supported: vec!["code", "id_token", "id_token token"],
+			grant_types_supported: vec![AUTHORIZATION_CODE_GRANT_TYPE, REFRESH_TOKEN_GRANT_TYPE, TOKEN_EXCHANGE_GRANT_TYPE, CIBA_GRANT_TYPE],
 			id_token_signing_alg_values_supported: vec!["RS256"],
 			userinfo_signing_alg_values_supported: vec!["none"],
-			claims_supported: vec!["sub", "email", "preferred_username", "name"],
+			dpop_signing_alg_values_supported: vec!["ES256", "RS256"],
+			claims_supported: SUPPORTED_CLAIMS.to_vec(),
+			claims_parameter_supported: true,
+			backchannel_token_delivery_modes_supported: vec!["poll", "ping"],
+			backchannel_user_code_parameter_supported: false,
 
 			// Pairwise would require a different username per client, too much hassle
 			subject_types_supported: vec!["public"],
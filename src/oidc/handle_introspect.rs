@@ -0,0 +1,166 @@
+use actix_web::{post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppErrorKind, Response};
+use crate::oidc::client::OIDCClient;
+use crate::oidc::dpop::Confirmation;
+use crate::oidc::handle_token::BearerTokenMetadata;
+use crate::token::OIDCBearerToken;
+use crate::CONFIG;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct IntrospectRequest {
+	pub token: String,
+	pub token_type_hint: Option<String>,
+	pub client_id: Option<String>,
+	pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct IntrospectResponse {
+	pub active: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub client_id: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub username: Option<String>,
+	#[serde(rename = "sub", skip_serializing_if = "Option::is_none")]
+	pub user: Option<String>,
+	#[serde(rename = "aud", skip_serializing_if = "Option::is_none")]
+	pub audience: Option<Vec<String>>,
+	#[serde(rename = "exp", skip_serializing_if = "Option::is_none")]
+	pub expires_at: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub token_type: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub scope: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub cnf: Option<Confirmation>,
+}
+
+#[post("/oidc/introspect")]
+pub async fn introspect(db: web::Data<reindeer::Db>, introspect_req: web::Form<IntrospectRequest>) -> Response {
+	let client_id = introspect_req.client_id.as_ref().ok_or(AppErrorKind::NoClientID)?;
+	let client_secret = introspect_req.client_secret.as_ref().ok_or(AppErrorKind::NoClientSecret)?;
+	let client = OIDCClient::from_credentials(&*CONFIG.read().await, client_id, client_secret)?;
+
+	let inactive = Ok(HttpResponse::Ok().json(IntrospectResponse::default()));
+
+	let Ok(token) = OIDCBearerToken::from_code(&db, &introspect_req.token).await else {
+		return inactive;
+	};
+
+	let Some(metadata) = BearerTokenMetadata::from_token(&token)? else {
+		return inactive;
+	};
+
+	// Tokens restricted to resources are only visible to the clients that serve them,
+	// unrestricted ones only to the client that they were issued to
+	let audience = if metadata.audience.is_empty() {
+		vec![metadata.client_id.clone()]
+	} else {
+		metadata.audience.clone()
+	};
+
+	if !client.is_audience_of(&audience) {
+		log::warn!("Client {} tried to introspect a token meant for {:?}", &client.id, &audience);
+		return inactive;
+	}
+
+	Ok(HttpResponse::Ok().json(IntrospectResponse {
+		active: true,
+		token_type: Some(metadata.token_type()),
+		scope: metadata.scope.clone(),
+		client_id: Some(metadata.client_id),
+		username: Some(token.user.username.clone()),
+		user: Some(token.user.email.clone()),
+		audience: Some(audience),
+		expires_at: Some(token.expires_at.and_utc().timestamp()),
+		cnf: metadata.jkt.map(|jkt| Confirmation { jkt }),
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::oidc::handle_userinfo;
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_introspect() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.service(introspect)
+				.service(handle_userinfo::userinfo)
+		)
+		.await;
+
+		let introspect_token = |token: &str| actix_test::TestRequest::post()
+			.uri("/oidc/introspect")
+			.set_form(&IntrospectRequest {
+				token: token.to_string(),
+				token_type_hint: None,
+				client_id: Some("my_client".to_string()),
+				client_secret: Some("my_secret".to_string()),
+			})
+			.to_request();
+
+		let new_token = |client_id: &str, audience: &[&str]| {
+			let metadata = BearerTokenMetadata {
+				client_id: client_id.to_string(),
+				audience: audience.iter().map(|a| a.to_string()).collect(),
+				jkt: None,
+				scope: None,
+			};
+			OIDCBearerToken::new(db, user.clone(), None, Some(String::try_from(&metadata).unwrap()))
+		};
+
+		// Unrestricted token of the client itself
+		let token = new_token("my_client", &[]).await.unwrap();
+		let resp = actix_test::call_service(&mut app, introspect_token(&token.code)).await;
+		let body: IntrospectResponse = actix_test::read_body_json(resp).await;
+		assert!(body.active);
+		assert_eq!(body.user.unwrap(), "valid@example.com");
+
+		// Token meant for a resource of the client
+		let token = new_token("other_client", &["https://api.example.com"]).await.unwrap();
+		let resp = actix_test::call_service(&mut app, introspect_token(&token.code)).await;
+		let body: IntrospectResponse = actix_test::read_body_json(resp).await;
+		assert!(body.active);
+
+		// Resource restricted tokens are not valid for userinfo
+		let req = actix_test::TestRequest::get()
+			.uri("/oidc/userinfo")
+			.append_header(("Authorization", format!("Bearer {}", token.code)))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		// Token meant for someone else
+		let token = new_token("other_client", &["https://other.example.com"]).await.unwrap();
+		let resp = actix_test::call_service(&mut app, introspect_token(&token.code)).await;
+		let body: IntrospectResponse = actix_test::read_body_json(resp).await;
+		assert!(!body.active);
+
+		// DPoP bound tokens are reported as such
+		let token = OIDCBearerToken::new(db, user.clone(), None, Some(String::try_from(&BearerTokenMetadata {
+			client_id: "my_client".to_string(),
+			audience: vec![],
+			jkt: Some("some_jkt".to_string()),
+			scope: None,
+		}).unwrap())).await.unwrap();
+		let resp = actix_test::call_service(&mut app, introspect_token(&token.code)).await;
+		let body: IntrospectResponse = actix_test::read_body_json(resp).await;
+		assert_eq!(body.token_type.unwrap(), "DPoP");
+		assert_eq!(body.cnf.unwrap().jkt, "some_jkt");
+
+		let resp = actix_test::call_service(&mut app, introspect_token("nonexistent_token")).await;
+		let body: IntrospectResponse = actix_test::read_body_json(resp).await;
+		assert!(!body.active);
+	}
+}
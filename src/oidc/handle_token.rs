@@ -1,23 +1,47 @@
+use std::collections::BTreeMap;
+
 use actix_web::{post, web, HttpRequest, HttpResponse};
-use chrono::Utc;
 use jwt_simple::algorithms::RS256KeyPair;
-use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder as _};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-use crate::error::{AppErrorKind, Response};
-use crate::token::{OIDCBearerToken, OIDCCodeToken};
+use crate::error::{AppErrorKind, Response, Result};
+use crate::token::{OIDCBearerToken, OIDCCodeToken, OIDCRefreshToken, Token, TokenKindType};
+use crate::user::User;
+use crate::oidc::ciba::{ciba, CIBA_GRANT_TYPE};
+use crate::oidc::client::{ClientType, OIDCClient};
+use crate::oidc::consumed_code::ConsumedOIDCCode;
+use crate::oidc::dpop::{proof_from_request, DPOP_TOKEN_TYPE};
 use crate::oidc::handle_authorize::AuthorizeRequest;
+use crate::oidc::refresh_token::{refresh_token, REFRESH_TOKEN_GRANT_TYPE};
+use crate::oidc::token_exchange::{token_exchange, TOKEN_EXCHANGE_GRANT_TYPE};
 use crate::CONFIG;
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub const AUTHORIZATION_CODE_GRANT_TYPE: &str = "authorization_code";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct TokenRequest {
 	pub grant_type: String,
-	pub code: String,
+	pub code: Option<String>,
 	pub client_id: Option<String>,
 	pub client_secret: Option<String>,
 	pub code_verifier: Option<String>,
 	pub redirect_uri: Option<String>,
+
+	// Token exchange (RFC 8693) parameters
+	pub subject_token: Option<String>,
+	pub subject_token_type: Option<String>,
+	pub requested_token_type: Option<String>,
+	pub audience: Option<String>,
+	pub scope: Option<String>,
+
+	// Resource indicators (RFC 8707) parameter
+	pub resource: Option<String>,
+
+	// Refresh token grant parameter
+	pub refresh_token: Option<String>,
+
+	// CIBA grant parameter
+	pub auth_req_id: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -25,91 +49,259 @@ pub struct TokenResponse {
 	pub access_token: String,
 	pub token_type: String,
 	pub expires_in: i64,
-	pub id_token: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub id_token: Option<String>,
 	pub refresh_token: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub issued_token_type: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TokenErrorResponse {
+	pub error: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error_description: Option<String>,
+}
+
+/// The `typ` claim of the JWTs signed with the OIDC key, so that a JWT
+/// issued for one purpose can't be passed off as another kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum JWTType {
+	#[serde(rename = "ID")]
+	ID,
+	/// The access tokens of the token exchange
+	#[serde(rename = "Bearer")]
+	Bearer,
+	#[serde(rename = "Registry")]
+	Registry,
+	/// The user assertions of the auth-url responses
+	#[serde(rename = "AuthUrl")]
+	AuthUrl,
+}
+
+/// The `exp` and `iat` claims are set by `jwt_simple::Claims`,
+/// defining them here as well would produce duplicate keys that can't be verified
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct JWTData {
 	#[serde(rename = "sub")]
 	pub user: String,
 	#[serde(rename = "aud")]
-	pub client_id: String,
+	pub audience: Vec<String>,
 	#[serde(rename = "iss")]
 	pub from_url: String,
-	#[serde(rename = "exp")]
-	pub expires_at: u64,
-	pub iat: u64,
+	#[serde(rename = "typ", default, skip_serializing_if = "Option::is_none")]
+	pub token_type: Option<JWTType>,
+	/// The individual claims requested with the `claims` parameter
+	#[serde(flatten)]
+	pub claims: BTreeMap<String, serde_json::Value>,
 }
 
 impl JWTData {
-	pub async fn new(base_url: String) -> Self {
-		let config = CONFIG.read().await;
-		let expiry = Utc::now() + config.session_duration;
+	pub fn new(base_url: String) -> Self {
 		JWTData {
 			user: String::default(),
-			client_id: String::default(),
+			audience: Vec::default(),
 			from_url: base_url,
-			expires_at: expiry.timestamp() as u64,
-			iat: Utc::now().timestamp() as u64,
+			token_type: None,
+			claims: BTreeMap::new(),
+		}
+	}
+}
+
+/// The metadata of the `OIDCBearerToken`s, describing who the token is for
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct BearerTokenMetadata {
+	pub client_id: String,
+	/// The resources (RFC 8707) that the token is restricted to - empty means no restriction
+	#[serde(default)]
+	pub audience: Vec<String>,
+	/// The thumbprint of the DPoP key (RFC 9449) that the token is bound to
+	pub jkt: Option<String>,
+	/// The scopes that were granted to the client
+	pub scope: Option<String>,
+}
+
+impl BearerTokenMetadata {
+	pub fn from_token<K: TokenKindType>(issued: &Token<K>) -> Result<Option<Self>> {
+		issued.metadata
+			.clone()
+			.map(Self::try_from)
+			.transpose()
+			.map_err(Into::into)
+	}
+
+	/// Issues a new access token for the client, along with a refresh token
+	/// if the client is configured to get them and the user granted `offline_access`
+	pub async fn issue(&self, db: &reindeer::Db, client: &OIDCClient, user: User, bound_to: Option<String>) -> Result<(OIDCBearerToken, Option<OIDCRefreshToken>)> {
+		let config = CONFIG.read().await;
+		let metadata = Some(String::try_from(self)?);
+
+		let bearer = OIDCBearerToken::new_with_duration(db, user.clone(), bound_to.clone(), metadata.clone(), Some(client.get_access_token_duration(&config))).await?;
+		let offline_access = self.scope.as_deref().unwrap_or_default().split_whitespace().any(|s| s == "offline_access");
+		let refresh = if client.refresh_token_duration.is_some() && offline_access {
+			Some(OIDCRefreshToken::new_with_duration(db, user, bound_to, metadata, client.refresh_token_duration).await?)
+		} else {
+			None
+		};
+
+		Ok((bearer, refresh))
+	}
+
+	pub fn token_type(&self) -> String {
+		if self.jkt.is_some() {
+			DPOP_TOKEN_TYPE.to_string()
+		} else {
+			"Bearer".to_string()
 		}
 	}
 }
 
+impl TryFrom<String> for BearerTokenMetadata {
+	type Error = serde_qs::Error;
+	fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+		serde_qs::from_str(&value)
+	}
+}
+
+impl TryFrom<&BearerTokenMetadata> for String {
+	type Error = serde_qs::Error;
+	fn try_from(value: &BearerTokenMetadata) -> std::result::Result<Self, Self::Error> {
+		serde_qs::to_string(&value)
+	}
+}
+
 #[post("/oidc/token")]
 pub async fn token(req: HttpRequest, db: web::Data<reindeer::Db>, token_req: web::Form<TokenRequest>, jwt_keypair: web::Data<RS256KeyPair>) -> Response {
 	#[cfg(debug_assertions)]
 	log::info!("Token request: {:?}", token_req);
 
-	let session = OIDCCodeToken::from_code(&db, &token_req.code).await?;
+	let result = match token_req.grant_type.as_str() {
+		AUTHORIZATION_CODE_GRANT_TYPE => authorization_code(req, db, token_req.into_inner(), jwt_keypair).await,
+		REFRESH_TOKEN_GRANT_TYPE => refresh_token(req, db, token_req.into_inner()).await,
+		TOKEN_EXCHANGE_GRANT_TYPE => token_exchange(req, db, token_req.into_inner(), jwt_keypair).await,
+		CIBA_GRANT_TYPE => ciba(req, db, token_req.into_inner(), jwt_keypair).await,
+		_ => Err(AppErrorKind::UnsupportedGrantType.into()),
+	};
+
+	token_error_response(result)
+}
+
+/// Clients expect the error codes of RFC 6749 section 5.2 (and CIBA section 11) as JSON to know what went wrong
+pub fn token_error_response(result: Response) -> Response {
+	let Err(e) = &result else {
+		return result;
+	};
+
+	let code = [
+		(AppErrorKind::InvalidScope, "invalid_scope"),
+		(AppErrorKind::UnknownUserID, "unknown_user_id"),
+		(AppErrorKind::CIBANotAllowed, "unauthorized_client"),
+		(AppErrorKind::CIBAAuthorizationPending, "authorization_pending"),
+		(AppErrorKind::CIBASlowDown, "slow_down"),
+		(AppErrorKind::CIBARequestExpired, "expired_token"),
+		(AppErrorKind::CIBAAccessDenied, "access_denied"),
+	]
+		.into_iter()
+		.find(|(kind, _)| e.is(kind.clone()));
+
+	match code {
+		Some((kind, error)) => Ok(HttpResponse::BadRequest().json(TokenErrorResponse {
+			error: error.to_string(),
+			error_description: Some(kind.to_string()),
+		})),
+		None => result,
+	}
+}
+
+async fn authorization_code(req: HttpRequest, db: web::Data<reindeer::Db>, token_req: TokenRequest, jwt_keypair: web::Data<RS256KeyPair>) -> Response {
+	let code = token_req.code.as_ref().ok_or(AppErrorKind::InvalidOIDCCode)?;
+
+	// The code might have been stolen, so whatever was issued from it can't be trusted anymore
+	if let Some(consumed) = ConsumedOIDCCode::from_code(&db, code)? {
+		consumed.revoke(&db).await?;
+		return Err(AppErrorKind::OIDCCodeReplayed.into());
+	}
+
+	// The code is only spent once the client proved it's the one it was issued to,
+	// otherwise anyone holding it could burn it and have the legitimate exchange treated as a replay
+	let session = OIDCCodeToken::peek(&db, code).await?;
 	println!("Session: {:?}", session);
 	let auth_req = AuthorizeRequest::try_from(session.metadata.ok_or(AppErrorKind::MissingMetadata)?)?;
 	let config = CONFIG.read().await;
 
-	// TODO: Check the request origin
-	if let Some(code_verifier) = token_req.code_verifier.clone() {
-		// We're using PCRE with code_challenge - code_verifier
-		// Client secret is not required and only the request origin should be checked
-		let mut hasher = Sha256::new();
-		hasher.update(code_verifier.as_bytes());
-		let generated_code_challenge_bytes = hasher.finalize().clone();
-		let generated_code_challenge = Base64UrlSafeNoPadding::encode_to_string(generated_code_challenge_bytes)?;
-
-		if Some(generated_code_challenge) != auth_req.code_challenge {
-			return Err(AppErrorKind::InvalidCodeVerifier.into());
-		}
-	} else if let Some(req_client_secret) = token_req.client_secret.clone() {
-		// We're using client_id - client_secret
-		let req_client_id = token_req.client_id.clone().ok_or(AppErrorKind::NoClientID)?;
-		let session_client_id = auth_req.client_id.clone();
-		let config_client = config.oidc_clients
-			.iter()
-			.find(|c|
-				session.user.has_any_realm(&c.realms) &&
-				c.id == session_client_id)
-			.ok_or(AppErrorKind::InvalidClientID)?;
-
-		if req_client_id != session_client_id {
-			return Err(AppErrorKind::NotMatchingClientID.into());
-		}
+	let client = OIDCClient::from_id(&config, &auth_req.client_id)?;
+	let req_client_id = token_req.client_id.as_ref().ok_or(AppErrorKind::NoClientID)?;
+	if req_client_id != &client.id {
+		return Err(AppErrorKind::NotMatchingClientID.into());
+	}
 
-		if config_client.id != req_client_id || config_client.secret != req_client_secret {
-			return Err(AppErrorKind::InvalidClientSecret.into());
+	// The redirect_uri has to be identical to the one of the authorization request (RFC 6749 section 4.1.3)
+	if let Some(auth_redirect_uri) = &auth_req.redirect_uri {
+		let req_redirect_uri = token_req.redirect_uri.as_ref().ok_or(AppErrorKind::InvalidRedirectUri)?;
+		if urlencoding::decode(req_redirect_uri)? != urlencoding::decode(auth_redirect_uri)? {
+			return Err(AppErrorKind::InvalidRedirectUri.into());
 		}
-	} else {
-		return Err(AppErrorKind::NoClientCredentialsProvided.into());
 	}
 
+	// A code that was bound with PKCE can't be redeemed without the verifier
+	// TODO: Check the request origin
+	match (&auth_req.code_challenge, &token_req.code_verifier) {
+		(Some(_), Some(code_verifier)) => auth_req.verify_code_verifier(code_verifier)?,
+		(None, None) if !client.requires_pkce() => {},
+		_ => return Err(AppErrorKind::InvalidCodeVerifier.into()),
+	}
+
+	// PKCE doesn't replace the authentication of confidential clients
+	if client.client_type == ClientType::Confidential {
+		let req_client_secret = token_req.client_secret.as_ref().ok_or(AppErrorKind::NoClientCredentialsProvided)?;
+		OIDCClient::from_credentials(&config, &client.id, req_client_secret)?;
+	}
+
+	let session = OIDCCodeToken::from_code(&db, code).await?;
+	let mut consumed = ConsumedOIDCCode::new(&db, &session)?;
+
+	if !session.user.has_any_realm(&client.realms) {
+		return Err(AppErrorKind::InvalidClientID.into());
+	}
+
+	// The resource can be narrowed down (or set) during the token request
+	let resource = match (&token_req.resource, &auth_req.resource) {
+		(Some(req_resource), Some(auth_resource)) if req_resource != auth_resource => return Err(AppErrorKind::InvalidTarget.into()),
+		(Some(resource), _) | (None, Some(resource)) => Some(resource.clone()),
+		(None, None) => None,
+	};
+	client.check_resource(resource.as_ref())?;
+
+	let jkt = proof_from_request(&db, &req, None).await?;
+	if client.dpop_bound_access_tokens && jkt.is_none() {
+		return Err(AppErrorKind::DPoPRequired.into());
+	}
+
+	let bearer_metadata = BearerTokenMetadata {
+		client_id: auth_req.client_id.clone(),
+		audience: resource.into_iter().collect(),
+		jkt,
+		scope: Some(auth_req.scope.clone()),
+	};
+
 	let base_url = config.url_from_request(&req);
-	let id_token = auth_req.generate_id_token(&session.user, base_url, jwt_keypair.as_ref()).await?;
-	let access_token = OIDCBearerToken::new(&db, session.user, session.bound_to, None).await?.code;
+	let id_token = auth_req.generate_id_token(&session.user, base_url, jwt_keypair.as_ref(), client.get_id_token_duration(&config)).await?;
+	let client = client.clone();
+	drop(config);
+
+	let (bearer, refresh) = bearer_metadata.issue(&db, &client, session.user, session.bound_to).await?;
+	consumed.add_token(&db, &bearer)?;
+	if let Some(refresh) = &refresh {
+		consumed.add_token(&db, refresh)?;
+	}
 
 	Ok(HttpResponse::Ok().json(TokenResponse {
-		access_token,
-		token_type: "Bearer".to_string(),
-		expires_in: config.session_duration.num_seconds(),
-		id_token,
-		refresh_token: None,
+		access_token: bearer.code.clone(),
+		token_type: bearer_metadata.token_type(),
+		expires_in: bearer.expires_in(),
+		id_token: Some(id_token),
+		refresh_token: refresh.map(|r| r.code),
+		issued_token_type: None,
 	}))
 	// Either respond access_token=<token>&token_type=<type>&expires_in=<seconds>&refresh_token=<token>&id_token=<token>
 	// TODO: Send error response
@@ -2,10 +2,12 @@ use actix_web::{get, web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
 
 use crate::error::{AppErrorKind, Response, Result};
+use crate::oidc::dpop::{proof_from_request, DPOP_TOKEN_TYPE};
+use crate::oidc::handle_token::BearerTokenMetadata;
 use crate::token::OIDCBearerToken;
-use crate::user::User;
+use crate::CONFIG;
 
-pub async fn token_from_request(db: &reindeer::Db, req: HttpRequest) -> Result<User> {
+pub async fn token_from_request(db: &reindeer::Db, req: &HttpRequest) -> Result<OIDCBearerToken> {
 	let Some(auth_header) = req.headers().get("Authorization") else {
 		return Err(AppErrorKind::MissingAuthorizationHeader.into())
 	};
@@ -16,7 +18,7 @@ pub async fn token_from_request(db: &reindeer::Db, req: HttpRequest) -> Result<U
 
 	let auth_header_parts = auth_header_str.split_whitespace().collect::<Vec<&str>>();
 
-	if auth_header_parts.len() != 2 || auth_header_parts[0] != "Bearer" {
+	if auth_header_parts.len() != 2 || (auth_header_parts[0] != "Bearer" && auth_header_parts[0] != DPOP_TOKEN_TYPE) {
 		return Err(AppErrorKind::InvalidAuthorizationHeader.into())
 	}
 
@@ -24,7 +26,22 @@ pub async fn token_from_request(db: &reindeer::Db, req: HttpRequest) -> Result<U
 		return Err(AppErrorKind::InvalidAuthorizationHeader.into())
 	};
 
-	Ok(OIDCBearerToken::from_code(db, &auth.to_string()).await?.user)
+	let token = OIDCBearerToken::from_code(db, &auth.to_string()).await?;
+	let jkt = BearerTokenMetadata::from_token(&token)?.and_then(|m| m.jkt);
+
+	// DPoP bound tokens (RFC 9449) need the DPoP scheme and a proof by the same key
+	if jkt.is_some() || auth_header_parts[0] == DPOP_TOKEN_TYPE {
+		if auth_header_parts[0] != DPOP_TOKEN_TYPE {
+			return Err(AppErrorKind::DPoPRequired.into())
+		}
+
+		let proof_jkt = proof_from_request(db, req, Some(auth)).await?.ok_or(AppErrorKind::DPoPRequired)?;
+		if jkt.is_some_and(|jkt| jkt != proof_jkt) {
+			return Err(AppErrorKind::InvalidDPoPProof.into())
+		}
+	}
+
+	Ok(token)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -39,8 +56,21 @@ pub struct UserInfoResponse<'a> {
 
 #[get("/oidc/userinfo")]
 pub async fn userinfo(db: web::Data<reindeer::Db>, req: HttpRequest) -> Response {
-	let user = token_from_request(&db, req).await?;
+	let token = token_from_request(&db, &req).await?;
+
+	// Tokens restricted to resources (RFC 8707) are only valid here if they're meant for us
+	if let Some(metadata) = BearerTokenMetadata::from_token(&token)? {
+		let base_url = CONFIG.read().await.url_from_request(&req);
+		let userinfo_url = format!("{}/oidc/userinfo", base_url);
 
+		if !metadata.audience.is_empty() && !metadata.audience.iter().any(|a| a == &base_url || a == &userinfo_url) {
+			return Err(AppErrorKind::InvalidTokenAudience.into());
+		}
+	}
+
+	let user = token.user;
+
+	// All the supported claims are always returned, which covers the userinfo part of the `claims` parameter
 	let resp = UserInfoResponse {
 		user: &user.email,
 		name: &user.name,
@@ -51,3 +81,65 @@ pub async fn userinfo(db: web::Data<reindeer::Db>, req: HttpRequest) -> Response
 
 	Ok(HttpResponse::Ok().json(resp))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::oidc::dpop::DPOP_HEADER;
+	use crate::oidc::dpop::tests::{create_key, create_proof};
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_userinfo_dpop() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.service(userinfo)
+		)
+		.await;
+
+		let userinfo_url = "http://localhost:8080/oidc/userinfo";
+		let userinfo_request = |scheme: &str, token: &str, proof: Option<String>| {
+			let mut req = actix_test::TestRequest::get()
+				.uri("/oidc/userinfo")
+				.append_header(("Authorization", format!("{} {}", scheme, token)));
+			if let Some(proof) = proof {
+				req = req.append_header((DPOP_HEADER, proof));
+			}
+			req.to_request()
+		};
+
+		// DPoP bound tokens need a proof by the bound key
+		let key = create_key();
+		let (_, jkt) = create_proof(&key, "GET", userinfo_url, None);
+		let token = OIDCBearerToken::new(db, user.clone(), None, Some(String::try_from(&BearerTokenMetadata {
+			client_id: "my_client".to_string(),
+			audience: vec![],
+			jkt: Some(jkt),
+			scope: None,
+		}).unwrap())).await.unwrap();
+
+		let resp = actix_test::call_service(&mut app, userinfo_request("Bearer", &token.code, None)).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		let resp = actix_test::call_service(&mut app, userinfo_request(DPOP_TOKEN_TYPE, &token.code, None)).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+		let (proof, _) = create_proof(&create_key(), "GET", userinfo_url, Some(&token.code));
+		let resp = actix_test::call_service(&mut app, userinfo_request(DPOP_TOKEN_TYPE, &token.code, Some(proof))).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+		let (proof, _) = create_proof(&key, "GET", userinfo_url, Some(&token.code));
+		let resp = actix_test::call_service(&mut app, userinfo_request(DPOP_TOKEN_TYPE, &token.code, Some(proof.clone()))).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+
+		// The same proof can't be used twice
+		let resp = actix_test::call_service(&mut app, userinfo_request(DPOP_TOKEN_TYPE, &token.code, Some(proof))).await;
+		assert_ne!(resp.status(), StatusCode::OK);
+	}
+}
@@ -0,0 +1,101 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppErrorKind, Response};
+use crate::CONFIG;
+
+pub const OIDC_ISSUER_REL: &str = "http://openid.net/specs/connect/1.0/issuer";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct WebFingerRequest {
+	pub resource: Option<String>,
+	pub rel: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct WebFingerLink {
+	pub rel: String,
+	pub href: String,
+}
+
+/// The JSON Resource Descriptor of RFC 7033 section 4.4
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct WebFingerResponse {
+	pub subject: String,
+	pub links: Vec<WebFingerLink>,
+}
+
+/// Issuer discovery of OIDC Discovery 1.0 section 2.
+/// Unknown users get a 404 unless `oidc_webfinger_unknown_users` is set,
+/// in which case every account gets the same answer and no emails are leaked.
+#[get("/.well-known/webfinger")]
+pub async fn webfinger(req: HttpRequest, webfinger_req: web::Query<WebFingerRequest>) -> Response {
+	let resource = webfinger_req.resource.as_ref().ok_or(AppErrorKind::MissingWebFingerResource)?;
+	let email = resource.strip_prefix("acct:").unwrap_or(resource);
+
+	let config = CONFIG.read().await;
+	if !config.oidc_webfinger_unknown_users && !config.users.iter().any(|u| u.email == email) {
+		return Err(AppErrorKind::NotFound.into());
+	}
+
+	// The rel parameter filters the links (RFC 7033 section 4.3)
+	let links = if webfinger_req.rel.as_ref().is_some_and(|rel| rel != OIDC_ISSUER_REL) {
+		vec![]
+	} else {
+		vec![WebFingerLink {
+			rel: OIDC_ISSUER_REL.to_string(),
+			href: config.url_from_request(&req),
+		}]
+	};
+
+	Ok(HttpResponse::Ok()
+		.content_type("application/jrd+json")
+		.append_header(("Access-Control-Allow-Origin", "*"))
+		.json(WebFingerResponse {
+			subject: resource.clone(),
+			links,
+		}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_webfinger() {
+		let mut app = actix_test::init_service(App::new().service(webfinger)).await;
+
+		let req = actix_test::TestRequest::get()
+			.uri("/.well-known/webfinger?resource=acct%3Avalid%40example.com")
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body: WebFingerResponse = actix_test::read_body_json(resp).await;
+		assert_eq!(body.subject, "acct:valid@example.com");
+		assert_eq!(body.links, vec![WebFingerLink {
+			rel: OIDC_ISSUER_REL.to_string(),
+			href: "http://localhost:8080".to_string(),
+		}]);
+
+		let req = actix_test::TestRequest::get()
+			.uri("/.well-known/webfinger?resource=acct%3Avalid%40example.com&rel=http%3A%2F%2Fwebfinger.net%2Frel%2Favatar")
+			.to_request();
+		let body: WebFingerResponse = actix_test::call_and_read_body_json(&mut app, req).await;
+		assert!(body.links.is_empty());
+
+		let req = actix_test::TestRequest::get()
+			.uri("/.well-known/webfinger?resource=acct%3Ainvalid%40example.com")
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+		let req = actix_test::TestRequest::get()
+			.uri("/.well-known/webfinger")
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+	}
+}
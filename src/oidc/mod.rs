@@ -3,12 +3,26 @@ use reindeer::{Db, Entity};
 
 use crate::config::{ConfigKV, ConfigKeys};
 
+pub mod ciba;
+pub mod claims;
 pub mod client;
+pub mod consumed_code;
+pub mod cors;
+pub mod dpop;
 pub mod handle_discover;
 pub mod handle_authorize;
+pub mod handle_check_session;
+pub mod handle_introspect;
 pub mod handle_token;
 pub mod handle_jwks;
 pub mod handle_userinfo;
+pub mod handle_webfinger;
+pub mod refresh_token;
+pub mod token_exchange;
+
+/// The scopes that clients can request, `offline_access` is only meaningful
+/// for clients that get refresh tokens
+pub const SUPPORTED_SCOPES: [&str; 4] = ["openid", "profile", "email", "offline_access"];
 
 pub async fn init(db: &Db) -> RS256KeyPair {
 	if let Ok(Some(keypair)) = ConfigKV::get(&ConfigKeys::JWTKeyPair, db) {
@@ -29,7 +43,9 @@ pub async fn init(db: &Db) -> RS256KeyPair {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::collections::BTreeMap;
 	use crate::token::MagicLinkToken;
+	use crate::SmtpTransport;
 	use crate::utils::tests::*;
 
 	use actix_session::storage::CookieSessionStore;
@@ -40,6 +56,7 @@ mod tests {
 	use actix_web::App;
 	use actix_web::test as actix_test;
 	use actix_web::http::StatusCode;
+	use jwt_simple::prelude::RSAPublicKeyLike;
 
 	use tests::handle_token::TokenRequest;
 	use tests::handle_token::TokenResponse;
@@ -57,7 +74,9 @@ mod tests {
 		let mut app = actix_test::init_service(
 			App::new()
 				.app_data(web::Data::new(db.clone()))
-				.app_data(web::Data::new(keypair))
+				.app_data(web::Data::new(keypair.clone()))
+				.app_data(web::Data::new(None::<SmtpTransport>))
+				.app_data(web::Data::new(None::<reqwest::Client>))
 				.service(crate::handle_login_link::login_link)
 				.service(handle_authorize::authorize_get)
 				.service(handle_authorize::authorize_post)
@@ -80,7 +99,7 @@ mod tests {
 
 		let req = actix_test::TestRequest::get()
 			.uri(format!(
-				"/oidc/authorize?client_id={}&redirect_uri={}&scope=openid%20profile%20email%20phone%20address&response_type=code&state={}",
+				"/oidc/authorize?client_id={}&redirect_uri={}&scope=openid%20profile%20email&response_type=code&state={}",
 				client_id,
 				redirect,
 				state
@@ -107,18 +126,21 @@ mod tests {
 		let resp = actix_test::call_service(&mut app, req).await;
 		assert_eq!(resp.status(), StatusCode::FOUND);
 		println!("Headers: {:?}", resp.headers());
-		assert!(resp.headers().get("Location").unwrap().to_str().unwrap().starts_with(redirect_url));
+		let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+		assert!(location.starts_with(redirect_url));
+		assert!(location.contains("&session_state="));
 
-		let headers = resp.headers().clone();
-		let cookie_header = headers.get("set-cookie").unwrap().to_str().unwrap();
-		let parsed_cookie = Cookie::parse_encoded(cookie_header).unwrap();
+		let browser_state_cookie = resp.response().cookies().find(|c| c.name() == crate::BROWSER_STATE_COOKIE).unwrap();
+		assert!(!browser_state_cookie.http_only().unwrap_or_default());
+		let parsed_cookie = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
 
 		let req = actix_test::TestRequest::get()
 			.uri(format!(
-				"/oidc/authorize?client_id={}&redirect_uri={}&scope=openid%20profile%20email%20phone%20address&response_type=code&state={}",
+				"/oidc/authorize?client_id={}&redirect_uri={}&scope=openid%20profile%20email&response_type=code&state={}&claims={}",
 				client_id,
 				redirect,
-				state
+				state,
+				urlencoding::encode(r#"{"id_token":{"email":{"essential":true}}}"#)
 			).as_str())
 			.cookie(parsed_cookie.clone())
 			.to_request();
@@ -126,9 +148,11 @@ mod tests {
 		assert_eq!(resp.status(), StatusCode::OK);
 		let body = actix_test::read_body(resp).await;
 		let body_str = std::str::from_utf8(&body).unwrap();
+		assert!(body_str.contains("My Client"));
+		assert!(body_str.contains("https://openidconnect.net/privacy"));
 		let html_parse = scraper::Html::parse_document(&body_str);
 		let a_href = html_parse
-			.select(&scraper::Selector::parse("a").unwrap())
+			.select(&scraper::Selector::parse("a[type=button]").unwrap())
 			.next()
 			.unwrap()
 			.value()
@@ -139,23 +163,48 @@ mod tests {
 		let code = location_url.query_pairs().find(|(k, _)| k == "code").unwrap().1.to_string();
 		println!("New Code: {}", code);
 
-		let req = actix_test::TestRequest::post()
+		let token_request_as = |req_client_id: &str, req_client_secret: &str, redirect_uri: &str| actix_test::TestRequest::post()
 			.uri("/oidc/token")
 			.set_form(&TokenRequest {
 				grant_type: "authorization_code".to_string(),
-				code,
-				client_id: Some(client_id.to_string()),
-				client_secret: Some(client_secret.to_string()),
+				code: Some(code.clone()),
+				client_id: Some(req_client_id.to_string()),
+				client_secret: Some(req_client_secret.to_string()),
 				code_verifier: None,
-				redirect_uri: Some(redirect.to_string()),
+				redirect_uri: Some(redirect_uri.to_string()),
+				subject_token: None,
+				subject_token_type: None,
+				requested_token_type: None,
+				audience: None,
+				scope: None,
+				resource: None,
+				refresh_token: None,
+				auth_req_id: None,
 			})
 			.to_request();
-		let resp = actix_test::call_service(&mut app, req).await;
+		let token_request = |redirect_uri: &str| token_request_as(client_id, client_secret, redirect_uri);
+
+		// Requests that fail the client checks don't spend the code
+		let resp = actix_test::call_service(&mut app, token_request_as(client_id, "wrong_secret", &redirect)).await;
+		assert_ne!(resp.status(), StatusCode::OK);
+		let resp = actix_test::call_service(&mut app, token_request_as("my_public_client", "", &redirect)).await;
+		assert_ne!(resp.status(), StatusCode::OK);
+		let resp = actix_test::call_service(&mut app, token_request(&urlencoding::encode("https://openidconnect.net/other"))).await;
+		assert_ne!(resp.status(), StatusCode::OK);
+
+		let resp = actix_test::call_service(&mut app, token_request(&redirect)).await;
 		assert_eq!(resp.status(), StatusCode::OK);
 		let body = actix_test::read_body(resp).await;
 		println!("Body: {:?}", body);
 		let resp_token = serde_json::from_slice::<TokenResponse>(&body).unwrap();
 
+		// The ID token must be verifiable using the public key (no duplicate claims etc.)
+		let id_token = resp_token.id_token.clone().unwrap();
+		let id_claims = keypair.public_key().verify_token::<BTreeMap<String, serde_json::Value>>(&id_token, None).unwrap();
+		assert_eq!(id_claims.subject.unwrap(), "valid@example.com");
+		assert_eq!(id_claims.custom.get("email").unwrap(), "valid@example.com");
+		assert!(!id_claims.custom.contains_key("name"));
+
 		let req = actix_test::TestRequest::get()
 			.uri("/oidc/userinfo")
 			.append_header(("Authorization", format!("Bearer {}", resp_token.access_token)))
@@ -170,6 +219,59 @@ mod tests {
 			email: "valid@example.com",
 			email_verified: true,
 			preferred_username: "valid",
-		})
+		});
+
+		// Replaying the code fails and revokes the access token issued from it
+		let resp = actix_test::call_service(&mut app, token_request(&redirect)).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+		let req = actix_test::TestRequest::get()
+			.uri("/oidc/userinfo")
+			.append_header(("Authorization", format!("Bearer {}", resp_token.access_token)))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_ne!(resp.status(), StatusCode::OK);
+	}
+
+	#[actix_web::test]
+	async fn test_login_hint() {
+		let db = &db_connect().await;
+		get_valid_user().await;
+		let secret = Key::from(&[0; 64]);
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(None::<SmtpTransport>))
+				.app_data(web::Data::new(None::<reqwest::Client>))
+				.service(handle_authorize::authorize_get)
+				.wrap(
+					SessionMiddleware::builder(
+						CookieSessionStore::default(),
+						secret
+					)
+					.build())
+		)
+		.await;
+
+		// The hint is carried over to the login page
+		let req = actix_test::TestRequest::get()
+			.uri("/oidc/authorize?client_id=my_client&redirect_uri=https%3A%2F%2Fopenidconnect.net%2Fcallback&scope=openid&response_type=code&login_hint=valid%40example.com")
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		let target = resp.headers().get("Location").unwrap().to_str().unwrap();
+		assert!(target.starts_with("http://localhost:8080/login"));
+		assert!(target.contains("login_hint=valid%40example.com"));
+
+		// Clients that opted in get the link sent straight away
+		let links_before = MagicLinkToken::get_with_filter(|t| t.user == "valid@example.com", db).unwrap().len();
+		let req = actix_test::TestRequest::get()
+			.uri("/oidc/authorize?client_id=my_public_client&redirect_uri=http%3A%2F%2F127.0.0.1%2Fcallback&scope=openid&response_type=code&code_challenge=E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM&code_challenge_method=S256&login_hint=valid%40example.com")
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+
+		let links = MagicLinkToken::get_with_filter(|t| t.user == "valid@example.com", db).unwrap();
+		assert!(links.len() > links_before);
 	}
 }
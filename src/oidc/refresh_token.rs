@@ -0,0 +1,192 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::info;
+
+use crate::error::{AppErrorKind, Response};
+use crate::oidc::client::{ClientType, OIDCClient};
+use crate::oidc::consumed_code::ConsumedOIDCCode;
+use crate::oidc::dpop::proof_from_request;
+use crate::oidc::handle_token::{BearerTokenMetadata, TokenRequest, TokenResponse};
+use crate::token::OIDCRefreshToken;
+use crate::CONFIG;
+
+pub const REFRESH_TOKEN_GRANT_TYPE: &str = "refresh_token";
+
+/// Exchanges a refresh token for a new access token (RFC 6749 section 6).
+/// Refresh tokens are rotated - each one can only be used once and a new one is issued along the access token.
+pub async fn refresh_token(req: HttpRequest, db: web::Data<reindeer::Db>, token_req: TokenRequest) -> Response {
+	let code = token_req.refresh_token.as_ref().ok_or(AppErrorKind::NoRefreshToken)?;
+	let refresh = OIDCRefreshToken::from_code(&db, code).await?;
+	let mut metadata = BearerTokenMetadata::from_token(&refresh)?.ok_or(AppErrorKind::MissingMetadata)?;
+
+	let client = {
+		let config = CONFIG.read().await;
+		let client = OIDCClient::from_id(&config, &metadata.client_id)?;
+		let req_client_id = token_req.client_id.as_ref().ok_or(AppErrorKind::NoClientID)?;
+		if req_client_id != &client.id {
+			return Err(AppErrorKind::NotMatchingClientID.into());
+		}
+
+		if client.client_type == ClientType::Confidential {
+			let req_client_secret = token_req.client_secret.as_ref().ok_or(AppErrorKind::NoClientCredentialsProvided)?;
+			OIDCClient::from_credentials(&config, &client.id, req_client_secret)?;
+		}
+
+		client.clone()
+	};
+
+	if !refresh.user.has_any_realm(&client.realms) {
+		return Err(AppErrorKind::InvalidClientID.into());
+	}
+
+	// Refresh tokens of DPoP bound clients are bound to the same key as well
+	let jkt = proof_from_request(&db, &req, None).await?;
+	if metadata.jkt.is_some() && metadata.jkt != jkt {
+		return Err(AppErrorKind::InvalidDPoPProof.into());
+	}
+
+	// The scope can only be narrowed down
+	if let Some(scope) = &token_req.scope {
+		client.check_scope(scope)?;
+		let granted = metadata.scope.clone().unwrap_or_default();
+		if scope.split_whitespace().any(|s| !granted.split_whitespace().any(|g| g == s)) {
+			return Err(AppErrorKind::InvalidScope.into());
+		}
+
+		metadata.scope = Some(scope.clone());
+	}
+
+	let (bearer, new_refresh) = metadata.issue(&db, &client, refresh.user.clone(), refresh.bound_to.clone()).await?;
+
+	// A replay of the code that started the chain has to revoke the rotated tokens as well
+	if let Some(mut consumed) = ConsumedOIDCCode::from_issued_token(&db, &refresh.code)? {
+		consumed.add_token(&db, &bearer)?;
+		if let Some(new_refresh) = &new_refresh {
+			consumed.add_token(&db, new_refresh)?;
+		}
+	}
+
+	info!("Client {} refreshed the token of {}", &client.id, &refresh.user.email);
+
+	Ok(HttpResponse::Ok().json(TokenResponse {
+		access_token: bearer.code.clone(),
+		token_type: metadata.token_type(),
+		expires_in: bearer.expires_in(),
+		id_token: None,
+		refresh_token: new_refresh.map(|r| r.code),
+		issued_token_type: None,
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::oidc::handle_token::{self, TokenErrorResponse};
+	use crate::token::{OIDCBearerToken, OIDCCodeToken, SessionToken};
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_refresh_token() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let keypair = jwt_simple::algorithms::RS256KeyPair::generate(2048).unwrap();
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(keypair))
+				.service(handle_token::token)
+		)
+		.await;
+
+		let session = SessionToken::new(db, user.clone(), None, None).await.unwrap();
+		let metadata = BearerTokenMetadata {
+			client_id: "my_public_client".to_string(),
+			audience: vec![],
+			jkt: None,
+			scope: Some("openid email offline_access".to_string()),
+		};
+		let refresh = OIDCRefreshToken::new(db, user, Some(session.code), Some(String::try_from(&metadata).unwrap())).await.unwrap();
+
+		let refresh_request = |refresh_token: &str, scope: Option<&str>| actix_test::TestRequest::post()
+			.uri("/oidc/token")
+			.set_form(&TokenRequest {
+				grant_type: REFRESH_TOKEN_GRANT_TYPE.to_string(),
+				client_id: Some("my_public_client".to_string()),
+				refresh_token: Some(refresh_token.to_string()),
+				scope: scope.map(str::to_string),
+				..Default::default()
+			})
+			.to_request();
+
+		let resp = actix_test::call_service(&mut app, refresh_request(&refresh.code, None)).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body: TokenResponse = actix_test::read_body_json(resp).await;
+		// The access_token_duration of the client is 1h
+		assert!(body.expires_in <= 3600 && body.expires_in > 3500);
+		let new_refresh = body.refresh_token.unwrap();
+		assert_ne!(new_refresh, refresh.code);
+
+		// Refresh tokens are rotated
+		let resp = actix_test::call_service(&mut app, refresh_request(&refresh.code, None)).await;
+		assert_ne!(resp.status(), StatusCode::OK);
+
+		// The scope can't be widened
+		let resp = actix_test::call_service(&mut app, refresh_request(&new_refresh, Some("openid profile"))).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+		let body: TokenErrorResponse = actix_test::read_body_json(resp).await;
+		assert_eq!(body.error, "invalid_scope");
+
+		// Narrowing the scope down without offline_access ends the chain
+		let refresh = OIDCRefreshToken::new(db, refresh.user.clone(), refresh.bound_to.clone(), refresh.metadata.clone()).await.unwrap();
+		let resp = actix_test::call_service(&mut app, refresh_request(&refresh.code, Some("openid email"))).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body: TokenResponse = actix_test::read_body_json(resp).await;
+		assert!(body.refresh_token.is_none());
+	}
+
+	#[actix_web::test]
+	async fn test_refresh_token_code_replay() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(jwt_simple::algorithms::RS256KeyPair::generate(2048).unwrap()))
+				.service(handle_token::token)
+		)
+		.await;
+
+		let metadata = BearerTokenMetadata {
+			client_id: "my_public_client".to_string(),
+			audience: vec![],
+			jkt: None,
+			scope: Some("openid offline_access".to_string()),
+		};
+		let client = OIDCClient::from_id(&*CONFIG.read().await, "my_public_client").unwrap().clone();
+		let code = OIDCCodeToken::new(db, user.clone(), None, None).await.unwrap();
+		let mut consumed = ConsumedOIDCCode::new(db, &code).unwrap();
+		let (_, refresh) = metadata.issue(db, &client, user, None).await.unwrap();
+		let refresh = refresh.unwrap();
+		consumed.add_token(db, &refresh).unwrap();
+
+		let resp = actix_test::call_service(&mut app, actix_test::TestRequest::post()
+			.uri("/oidc/token")
+			.set_form(&TokenRequest {
+				grant_type: REFRESH_TOKEN_GRANT_TYPE.to_string(),
+				client_id: Some("my_public_client".to_string()),
+				refresh_token: Some(refresh.code.clone()),
+				..Default::default()
+			})
+			.to_request()).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body: TokenResponse = actix_test::read_body_json(resp).await;
+		let rotated = body.refresh_token.unwrap();
+
+		// The replay of the code revokes the rotated tokens too
+		ConsumedOIDCCode::from_code(db, &code.code).unwrap().unwrap().revoke(db).await.unwrap();
+		assert!(OIDCRefreshToken::from_code(db, &rotated).await.is_err());
+		assert!(OIDCBearerToken::from_code(db, &body.access_token).await.is_err());
+	}
+}
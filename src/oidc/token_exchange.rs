@@ -0,0 +1,297 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use jwt_simple::prelude::*;
+use log::{info, warn};
+
+use crate::error::{AppErrorKind, Response, Result};
+use crate::oidc::client::OIDCClient;
+use crate::oidc::dpop::{proof_from_request, Confirmation, DPOP_TOKEN_TYPE};
+use crate::oidc::handle_token::{BearerTokenMetadata, JWTData, JWTType, TokenRequest, TokenResponse};
+use crate::token::OIDCBearerToken;
+use crate::user::User;
+use crate::CONFIG;
+
+pub const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+pub const ID_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:id_token";
+pub const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// The party that acts on behalf of the user (RFC 8693 section 4.1)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ActorClaim {
+	#[serde(rename = "sub")]
+	pub client_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExchangedJWTData {
+	#[serde(flatten)]
+	pub jwt: JWTData,
+	pub act: ActorClaim,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub scope: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub cnf: Option<Confirmation>,
+}
+
+/// The claims of a JWT subject token that matter to the exchange, the rest are ignored
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct SubjectClaims {
+	#[serde(default)]
+	typ: Option<JWTType>,
+	#[serde(default)]
+	scope: Option<String>,
+	#[serde(default)]
+	cnf: Option<Confirmation>,
+}
+
+/// The user that the subject token was issued for
+struct Subject {
+	user: User,
+	/// The unix timestamp that the subject token expires at
+	expires_at: i64,
+	/// The scopes granted to the subject token, which the exchanged token can't exceed
+	scope: String,
+}
+
+/// Checks that the subject token was issued to the requesting client, either directly or through
+/// an earlier exchange, and that the request proves the possession of the DPoP key it's bound to
+async fn get_subject(db: &reindeer::Db, token_req: &TokenRequest, jwt_keypair: &RS256KeyPair, client: &OIDCClient, jkt: Option<&String>) -> Result<Subject> {
+	let subject_token = token_req.subject_token.as_ref().ok_or(AppErrorKind::NoSubjectToken)?;
+
+	let (user, expires_at, scope, bound_jkt) = match token_req.subject_token_type.as_deref() {
+		Some(ACCESS_TOKEN_TYPE) => {
+			let token = OIDCBearerToken::from_code(db, subject_token).await?;
+			let metadata = BearerTokenMetadata::from_token(&token)?.ok_or(AppErrorKind::InvalidSubjectToken)?;
+			if metadata.client_id != client.id && !client.is_audience_of(&metadata.audience) {
+				warn!("Client {} sent the access token of client {} as the subject token", &client.id, &metadata.client_id);
+				return Err(AppErrorKind::InvalidSubjectToken.into());
+			}
+
+			(token.user, token.expires_at.and_utc().timestamp(), metadata.scope, metadata.jkt)
+		},
+		Some(subject_token_type @ (ID_TOKEN_TYPE | JWT_TOKEN_TYPE)) => {
+			let options = VerificationOptions {
+				allowed_audiences: Some(HashSet::from_strings(&[&client.id])),
+				..Default::default()
+			};
+			let claims = jwt_keypair
+				.public_key()
+				.verify_token::<SubjectClaims>(subject_token, Some(options))
+				.map_err(|_| AppErrorKind::InvalidSubjectToken)?;
+
+			// ID tokens carry no scopes, they only ever identify the user
+			let scope = match (subject_token_type, claims.custom.typ) {
+				(_, Some(JWTType::ID)) => Some("openid".to_string()),
+				(JWT_TOKEN_TYPE, Some(JWTType::Bearer)) => claims.custom.scope,
+				_ => return Err(AppErrorKind::InvalidSubjectToken.into()),
+			};
+
+			let email = claims.subject.ok_or(AppErrorKind::InvalidSubjectToken)?;
+			let user = User::from_config(&email).await.ok_or(AppErrorKind::InvalidSubjectToken)?;
+			let expires_at = claims.expires_at.ok_or(AppErrorKind::InvalidSubjectToken)?;
+
+			(user, expires_at.as_secs() as i64, scope, claims.custom.cnf.map(|cnf| cnf.jkt))
+		},
+		_ => return Err(AppErrorKind::UnsupportedTokenType.into()),
+	};
+
+	if bound_jkt.is_some() && bound_jkt.as_ref() != jkt {
+		warn!("Client {} sent a DPoP-bound subject token without a proof of its key", &client.id);
+		return Err(AppErrorKind::InvalidSubjectToken.into());
+	}
+
+	Ok(Subject {
+		user,
+		expires_at,
+		scope: scope.unwrap_or_default(),
+	})
+}
+
+/// Issues a JWT access token for the `audience` client on behalf of the user of the `subject_token`.
+/// The requesting client must be allowed to do so by its `token_exchange_targets`,
+/// and the resulting token can never outlive nor have more scopes than the subject token.
+pub async fn token_exchange(req: HttpRequest, db: web::Data<reindeer::Db>, token_req: TokenRequest, jwt_keypair: web::Data<RS256KeyPair>) -> Response {
+	let client_id = token_req.client_id.as_ref().ok_or(AppErrorKind::NoClientID)?;
+	let client_secret = token_req.client_secret.as_ref().ok_or(AppErrorKind::NoClientSecret)?;
+	let audience = token_req.audience.as_ref().ok_or(AppErrorKind::NoAudience)?;
+
+	let issued_token_type = match token_req.requested_token_type.as_deref() {
+		None | Some(ACCESS_TOKEN_TYPE) => ACCESS_TOKEN_TYPE,
+		Some(JWT_TOKEN_TYPE) => JWT_TOKEN_TYPE,
+		_ => return Err(AppErrorKind::UnsupportedTokenType.into()),
+	};
+
+	let client = OIDCClient::from_credentials(&*CONFIG.read().await, client_id, client_secret)?;
+
+	let jkt = proof_from_request(&db, &req, None).await?;
+	if client.dpop_bound_access_tokens && jkt.is_none() {
+		return Err(AppErrorKind::DPoPRequired.into());
+	}
+	let token_type = if jkt.is_some() { DPOP_TOKEN_TYPE } else { "Bearer" };
+
+	let Subject { user, expires_at: subject_expires_at, scope: subject_scope } = get_subject(&db, &token_req, &jwt_keypair, &client, jkt.as_ref()).await?;
+
+	let config = CONFIG.read().await;
+	if !client.token_exchange_targets.contains(audience) {
+		return Err(AppErrorKind::TokenExchangeNotAllowed.into());
+	}
+
+	let target = OIDCClient::from_id(&config, audience)?;
+
+	if !user.has_any_realm(&client.realms) || !user.has_any_realm(&target.realms) {
+		return Err(AppErrorKind::TokenExchangeNotAllowed.into());
+	}
+
+	target.check_resource(token_req.resource.as_ref())?;
+
+	// The exchanged token gets at most the scopes of the subject token, by default all the ones the target allows
+	let scope = match &token_req.scope {
+		Some(scope) => {
+			if scope.split_whitespace().any(|s| !subject_scope.split_whitespace().any(|granted| granted == s)) {
+				warn!("Client {} requested scopes {} beyond the subject token's {}", &client.id, scope, &subject_scope);
+				return Err(AppErrorKind::InvalidScope.into());
+			}
+			target.check_scope(scope)?;
+			scope.clone()
+		},
+		None => subject_scope
+			.split_whitespace()
+			.filter(|s| target.check_scope(s).is_ok())
+			.collect::<Vec<_>>()
+			.join(" "),
+	};
+
+	let expires_in = (subject_expires_at - Utc::now().timestamp()).min(client.get_access_token_duration(&config).num_seconds());
+	if expires_in <= 0 {
+		return Err(AppErrorKind::InvalidSubjectToken.into());
+	}
+
+	let jwt_data = ExchangedJWTData {
+		jwt: JWTData {
+			user: user.email.clone(),
+			audience: std::iter::once(target.id.clone())
+				.chain(token_req.resource.clone())
+				.collect(),
+			token_type: Some(JWTType::Bearer),
+			..JWTData::new(config.url_from_request(&req))
+		},
+		act: ActorClaim {
+			client_id: client.id.clone(),
+		},
+		scope: Some(scope).filter(|s| !s.is_empty()),
+		cnf: jkt.map(|jkt| Confirmation { jkt }),
+	};
+	let claims = Claims::with_custom_claims(jwt_data, Duration::from_secs(expires_in as u64));
+	let access_token = jwt_keypair.sign(claims)?;
+
+	info!("Client {} exchanged a token of {} for {}", &client.id, &user.email, &target.id);
+
+	Ok(HttpResponse::Ok().json(TokenResponse {
+		access_token,
+		token_type: token_type.to_string(),
+		expires_in,
+		id_token: None,
+		refresh_token: None,
+		issued_token_type: Some(issued_token_type.to_string()),
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::oidc::handle_authorize::AuthorizeRequest;
+	use crate::oidc::handle_token::{self, TokenErrorResponse};
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_token_exchange() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let keypair = RS256KeyPair::generate(2048).unwrap();
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(keypair.clone()))
+				.service(handle_token::token)
+		)
+		.await;
+
+		let exchange = |subject_token: &str, subject_token_type: &str, scope: Option<&str>| actix_test::TestRequest::post()
+			.uri("/oidc/token")
+			.set_form(&TokenRequest {
+				grant_type: TOKEN_EXCHANGE_GRANT_TYPE.to_string(),
+				client_id: Some("my_client".to_string()),
+				client_secret: Some("my_secret".to_string()),
+				subject_token: Some(subject_token.to_string()),
+				subject_token_type: Some(subject_token_type.to_string()),
+				audience: Some("my_public_client".to_string()),
+				scope: scope.map(str::to_string),
+				..Default::default()
+			})
+			.to_request();
+
+		let new_token = |client_id: &str, jkt: Option<&str>| {
+			let metadata = BearerTokenMetadata {
+				client_id: client_id.to_string(),
+				audience: vec![],
+				jkt: jkt.map(str::to_string),
+				scope: Some("openid email".to_string()),
+			};
+			OIDCBearerToken::new(db, user.clone(), None, Some(String::try_from(&metadata).unwrap()))
+		};
+
+		let token = new_token("my_client", None).await.unwrap();
+		let resp = actix_test::call_service(&mut app, exchange(&token.code, ACCESS_TOKEN_TYPE, None)).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body: TokenResponse = actix_test::read_body_json(resp).await;
+		let claims = keypair.public_key().verify_token::<SubjectClaims>(&body.access_token, None).unwrap();
+		assert_eq!(claims.custom.typ, Some(JWTType::Bearer));
+		assert_eq!(claims.custom.scope.as_deref(), Some("openid email"));
+
+		// The exchanged token can't have more scopes than the subject token
+		let resp = actix_test::call_service(&mut app, exchange(&token.code, ACCESS_TOKEN_TYPE, Some("openid profile"))).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+		let body: TokenErrorResponse = actix_test::read_body_json(resp).await;
+		assert_eq!(body.error, "invalid_scope");
+
+		// The subject token has to be issued to the requesting client
+		let token = new_token("my_public_client", None).await.unwrap();
+		let resp = actix_test::call_service(&mut app, exchange(&token.code, ACCESS_TOKEN_TYPE, None)).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+		// DPoP-bound subject tokens need a proof of their key
+		let token = new_token("my_client", Some("thumbprint")).await.unwrap();
+		let resp = actix_test::call_service(&mut app, exchange(&token.code, ACCESS_TOKEN_TYPE, None)).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+		let own_auth_req = AuthorizeRequest { client_id: "my_client".to_string(), ..Default::default() };
+		let other_auth_req = AuthorizeRequest { client_id: "my_public_client".to_string(), ..Default::default() };
+		let id_token_duration = chrono::Duration::try_minutes(5).unwrap();
+
+		let own_id_token = own_auth_req.generate_id_token(&user, "http://localhost:8080".to_string(), &keypair, id_token_duration).await.unwrap();
+		let resp = actix_test::call_service(&mut app, exchange(&own_id_token, ID_TOKEN_TYPE, Some("openid"))).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+
+		let resp = actix_test::call_service(&mut app, exchange(&own_id_token, ID_TOKEN_TYPE, Some("openid email"))).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+		let other_id_token = other_auth_req.generate_id_token(&user, "http://localhost:8080".to_string(), &keypair, id_token_duration).await.unwrap();
+		let resp = actix_test::call_service(&mut app, exchange(&other_id_token, ID_TOKEN_TYPE, None)).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+		// Other JWTs signed with the same key aren't subject tokens, even with the right audience
+		let jwt_data = JWTData {
+			user: user.email.clone(),
+			audience: vec!["my_client".to_string()],
+			token_type: Some(JWTType::Registry),
+			..JWTData::new("http://localhost:8080".to_string())
+		};
+		let registry_token = keypair.sign(Claims::with_custom_claims(jwt_data, Duration::from_secs(60))).unwrap();
+		let resp = actix_test::call_service(&mut app, exchange(&registry_token, JWT_TOKEN_TYPE, None)).await;
+		assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+	}
+}
@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::{SecondsFormat, Utc};
+use jwt_simple::prelude::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppErrorKind, Response, Result};
+use crate::oidc::handle_token::{BearerTokenMetadata, JWTData, JWTType};
+use crate::registry::{RegistryAccess, RegistryService};
+use crate::token::OIDCBearerToken;
+use crate::user::User;
+use crate::utils::random_string;
+use crate::CONFIG;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RegistryTokenResponse {
+	pub token: String,
+	/// The same as `token`, for OAuth 2.0 compatibility
+	pub access_token: String,
+	pub expires_in: i64,
+	pub issued_at: String,
+}
+
+/// The password of the Basic credentials is an access token issued by the OIDC token endpoint,
+/// the username has to be the username or the email of its user.
+/// Only tokens restricted to the registry are accepted, i.e. requested with the registry's `service`
+/// as their `resource` (RFC 8707) by a client that lists it in its `resources`, so that the access
+/// tokens of the other clients can't be turned into registry tokens
+async fn user_from_basic_auth(db: &reindeer::Db, req: &HttpRequest, service: &str) -> Result<User> {
+	let auth_header = req.headers()
+		.get("Authorization")
+		.ok_or(AppErrorKind::MissingAuthorizationHeader)?
+		.to_str()
+		.map_err(|_| AppErrorKind::InvalidAuthorizationHeader)?;
+	let encoded = auth_header.strip_prefix("Basic ").ok_or(AppErrorKind::InvalidAuthorizationHeader)?;
+	let decoded = openssl::base64::decode_block(encoded.trim()).map_err(|_| AppErrorKind::InvalidAuthorizationHeader)?;
+	let credentials = String::from_utf8(decoded).map_err(|_| AppErrorKind::InvalidAuthorizationHeader)?;
+	let (username, password) = credentials.split_once(':').ok_or(AppErrorKind::InvalidAuthorizationHeader)?;
+
+	let bearer = OIDCBearerToken::from_code(db, &password.to_string()).await?;
+	if bearer.user.username != username && bearer.user.email != username {
+		return Err(AppErrorKind::NotLoggedIn.into());
+	}
+
+	let metadata = BearerTokenMetadata::from_token(&bearer)?.ok_or(AppErrorKind::InvalidTokenAudience)?;
+
+	// There's no way to send a DPoP proof along with the Basic credentials
+	if metadata.jkt.is_some() {
+		return Err(AppErrorKind::DPoPRequired.into());
+	}
+
+	if !metadata.audience.iter().any(|a| a == service) {
+		return Err(AppErrorKind::InvalidTokenAudience.into());
+	}
+
+	Ok(bearer.user)
+}
+
+/// Docker distribution token authentication: `/registry/token?service=...&scope=...`,
+/// where `scope` may be repeated
+#[get("/registry/token")]
+pub async fn token(req: HttpRequest, db: web::Data<reindeer::Db>, jwt_keypair: web::Data<RS256KeyPair>) -> Response {
+	let mut service = None;
+	let mut requested = Vec::new();
+	for (key, value) in req.query_string().split('&').filter_map(|p| p.split_once('=')) {
+		let value = urlencoding::decode(&value.replace('+', " "))
+			.map_err(|_| AppErrorKind::InvalidRegistryService)?
+			.to_string();
+
+		match key {
+			"service" => service = Some(value),
+			"scope" => requested.extend(value.split(' ').filter_map(RegistryAccess::parse)),
+			_ => {},
+		}
+	}
+	let service = service.ok_or(AppErrorKind::InvalidRegistryService)?;
+
+	let user = match user_from_basic_auth(&db, &req, &service).await {
+		Ok(user) => user,
+		Err(e) => {
+			warn!("Registry token request for {} without valid credentials: {}", &service, e);
+			return Ok(HttpResponse::Unauthorized()
+				.insert_header(("WWW-Authenticate", format!(r#"Basic realm="{}""#, service)))
+				.finish());
+		},
+	};
+
+	let config = CONFIG.read().await;
+	let registry_service = RegistryService::from_service(&config, &service)?;
+	let access = registry_service.grant(&user, &requested);
+	let expires_in = config.registry_token_duration.num_seconds();
+
+	let mut claims = BTreeMap::new();
+	claims.insert("access".to_string(), serde_json::to_value(&access)?);
+	let jwt_data = JWTData {
+		user: user.email.clone(),
+		audience: vec![service.clone()],
+		token_type: Some(JWTType::Registry),
+		claims,
+		..JWTData::new(config.url_from_request(&req))
+	};
+	drop(config);
+
+	let claims = Claims::with_custom_claims(jwt_data, Duration::from_secs(expires_in as u64))
+		.with_jwt_id(random_string());
+	let registry_token = jwt_keypair.sign(claims)?;
+
+	info!("Issued registry token for {} to {} with {} repositories", &service, &user.email, access.len());
+
+	Ok(HttpResponse::Ok().json(RegistryTokenResponse {
+		token: registry_token.clone(),
+		access_token: registry_token,
+		expires_in,
+		issued_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::tests::*;
+
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[derive(Debug, Clone, Serialize, Deserialize)]
+	struct RegistryClaims {
+		access: Vec<RegistryAccess>,
+	}
+
+	#[actix_web::test]
+	async fn test_registry_token() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let keypair = RS256KeyPair::generate(2048).unwrap().with_key_id("default");
+		let public_key = keypair.public_key();
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(keypair))
+				.service(token)
+		)
+		.await;
+
+		let uri = "/registry/token?service=registry.example.com&scope=repository%3Aexample%2Fapp%3Apull%2Cpush&scope=repository:private/app:pull";
+		let req = actix_test::TestRequest::get().uri(uri).to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+		assert!(resp.headers().get("WWW-Authenticate").is_some());
+
+		let new_token = |audience: &[&str]| {
+			let metadata = BearerTokenMetadata {
+				client_id: "my_client".to_string(),
+				audience: audience.iter().map(|a| a.to_string()).collect(),
+				jkt: None,
+				scope: None,
+			};
+			OIDCBearerToken::new(db, user.clone(), None, Some(String::try_from(&metadata).unwrap()))
+		};
+
+		// Access tokens that aren't restricted to the registry can't be used
+		for bearer in [
+			OIDCBearerToken::new(db, user.clone(), None, None).await.unwrap(),
+			new_token(&[]).await.unwrap(),
+			new_token(&["https://api.example.com"]).await.unwrap(),
+		] {
+			let credentials = openssl::base64::encode_block(format!("valid:{}", bearer.code).as_bytes());
+			let req = actix_test::TestRequest::get()
+				.uri(uri)
+				.insert_header(("Authorization", format!("Basic {}", credentials)))
+				.to_request();
+			let resp = actix_test::call_service(&mut app, req).await;
+			assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+		}
+
+		let bearer = new_token(&["registry.example.com"]).await.unwrap();
+		let credentials = openssl::base64::encode_block(format!("valid:{}", bearer.code).as_bytes());
+		let req = actix_test::TestRequest::get()
+			.uri(uri)
+			.insert_header(("Authorization", format!("Basic {}", credentials)))
+			.to_request();
+		let resp: RegistryTokenResponse = actix_test::call_and_read_body_json(&mut app, req).await;
+		assert_eq!(resp.token, resp.access_token);
+
+		let options = VerificationOptions {
+			allowed_audiences: Some(HashSet::from_strings(&["registry.example.com"])),
+			..Default::default()
+		};
+		let claims = public_key.verify_token::<RegistryClaims>(&resp.token, Some(options)).unwrap();
+		assert_eq!(claims.subject.unwrap(), "valid@example.com");
+		assert_eq!(claims.custom.access, vec![RegistryAccess::parse("repository:example/app:pull,push").unwrap()]);
+
+		// The username has to belong to the token
+		let credentials = openssl::base64::encode_block(format!("integration:{}", bearer.code).as_bytes());
+		let req = actix_test::TestRequest::get()
+			.uri(uri)
+			.insert_header(("Authorization", format!("Basic {}", credentials)))
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+	}
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigFile;
+use crate::error::{AppErrorKind, Result};
+use crate::user::User;
+
+pub mod handle_token;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RegistryPermission {
+	pub realms: Vec<String>,
+	/// Repository names, a trailing `*` matches any repository with that prefix
+	pub repositories: Vec<String>,
+	/// Usually `pull` and/or `push`
+	pub actions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RegistryService {
+	/// The `service` the registry asks tokens for, it's the audience of the tokens
+	pub service: String,
+	pub permissions: Vec<RegistryPermission>,
+}
+
+/// A resource scope of the token request, e.g. `repository:samalba/my-app:pull,push`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RegistryAccess {
+	#[serde(rename = "type")]
+	pub resource_type: String,
+	pub name: String,
+	pub actions: Vec<String>,
+}
+
+impl RegistryAccess {
+	/// The name may contain a registry host with a port, so the actions are after the last colon
+	pub fn parse(scope: &str) -> Option<Self> {
+		let (resource_type, rest) = scope.split_once(':')?;
+		let (name, actions) = rest.rsplit_once(':')?;
+
+		Some(Self {
+			resource_type: resource_type.to_string(),
+			name: name.to_string(),
+			actions: actions.split(',').filter(|a| !a.is_empty()).map(str::to_string).collect(),
+		})
+	}
+}
+
+fn matches_repository(pattern: &str, name: &str) -> bool {
+	match pattern.strip_suffix('*') {
+		Some(prefix) => name.starts_with(prefix),
+		None => pattern == name,
+	}
+}
+
+impl RegistryService {
+	pub fn from_service<'a>(config: &'a ConfigFile, service: &str) -> Result<&'a Self> {
+		config.registry_services
+			.iter()
+			.find(|s| s.service == service)
+			.ok_or(AppErrorKind::InvalidRegistryService.into())
+	}
+
+	/// Narrows the requested access down to what the realms of the user allow,
+	/// leaving out the resources that the user can't do anything with
+	pub fn grant(&self, user: &User, requested: &[RegistryAccess]) -> Vec<RegistryAccess> {
+		requested
+			.iter()
+			.filter(|access| access.resource_type == "repository")
+			.map(|access| {
+				let allowed = self.permissions
+					.iter()
+					.filter(|p| user.has_any_realm(&p.realms) && p.repositories.iter().any(|r| matches_repository(r, &access.name)))
+					.flat_map(|p| p.actions.iter())
+					.collect::<BTreeSet<_>>();
+
+				RegistryAccess {
+					actions: access.actions.iter().filter(|a| allowed.contains(a)).cloned().collect(),
+					..access.clone()
+				}
+			})
+			.filter(|access| !access.actions.is_empty())
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::tests::*;
+	use crate::CONFIG;
+
+	#[actix_web::test]
+	async fn test_registry_grant() {
+		let user = get_valid_user().await;
+		let config = CONFIG.read().await;
+		let service = RegistryService::from_service(&config, "registry.example.com").unwrap();
+
+		let requested = [
+			"repository:example/app:pull,push",
+			"repository:library/alpine:pull,push",
+			"repository:private/app:pull",
+			"registry:catalog:*",
+		].map(|s| RegistryAccess::parse(s).unwrap());
+		assert_eq!(RegistryAccess::parse("repository:localhost:5000/alpine:pull").unwrap().name, "localhost:5000/alpine");
+
+		assert_eq!(service.grant(&user, &requested), vec![
+			RegistryAccess::parse("repository:example/app:pull,push").unwrap(),
+			RegistryAccess::parse("repository:library/alpine:pull").unwrap(),
+		]);
+	}
+}
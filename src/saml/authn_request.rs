@@ -0,0 +1,145 @@
+use std::io::Read;
+
+use flate2::read::DeflateDecoder;
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigFile;
+use crate::error::{AppErrorKind, Result};
+use crate::saml::service_provider::SAMLServiceProvider;
+use crate::saml::SAML_POST_BINDING;
+
+/// The parameters of both the HTTP-Redirect and the HTTP-POST bindings
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SAMLRequestParams {
+	#[serde(rename = "SAMLRequest")]
+	pub saml_request: String,
+	#[serde(rename = "RelayState")]
+	pub relay_state: Option<String>,
+}
+
+/// The parts of the `samlp:AuthnRequest` that matter to us
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct AuthnRequestXml {
+	#[serde(rename = "@ID")]
+	id: String,
+	#[serde(rename = "@AssertionConsumerServiceURL")]
+	acs_url: Option<String>,
+	#[serde(rename = "@ProtocolBinding")]
+	protocol_binding: Option<String>,
+	#[serde(rename = "Issuer")]
+	issuer: String,
+}
+
+/// A validated AuthnRequest, kept in the session while the user logs in
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AuthnRequest {
+	pub id: String,
+	pub issuer: String,
+	pub acs_url: String,
+	pub relay_state: Option<String>,
+}
+
+impl AuthnRequest {
+	/// HTTP-Redirect binding: the request is deflated and then base64 encoded (SAML Bindings section 3.4.4.1)
+	pub fn from_redirect(config: &ConfigFile, params: &SAMLRequestParams) -> Result<Self> {
+		let deflated = decode_base64(&params.saml_request)?;
+		let mut xml = String::new();
+		DeflateDecoder::new(deflated.as_slice())
+			.read_to_string(&mut xml)
+			.map_err(|_| AppErrorKind::InvalidSAMLRequest)?;
+
+		Self::from_xml(config, &xml, params.relay_state.clone())
+	}
+
+	/// HTTP-POST binding: the request is just base64 encoded (SAML Bindings section 3.5.4)
+	pub fn from_post(config: &ConfigFile, params: &SAMLRequestParams) -> Result<Self> {
+		let xml = String::from_utf8(decode_base64(&params.saml_request)?).map_err(|_| AppErrorKind::InvalidSAMLRequest)?;
+
+		Self::from_xml(config, &xml, params.relay_state.clone())
+	}
+
+	fn from_xml(config: &ConfigFile, xml: &str, relay_state: Option<String>) -> Result<Self> {
+		let request: AuthnRequestXml = quick_xml::de::from_str(xml).map_err(|_| AppErrorKind::InvalidSAMLRequest)?;
+
+		// The assertion can only be delivered with an auto-submitted form
+		if request.protocol_binding.as_ref().is_some_and(|b| b != SAML_POST_BINDING) {
+			return Err(AppErrorKind::InvalidSAMLRequest.into());
+		}
+
+		let sp = SAMLServiceProvider::from_entity_id(config, request.issuer.trim())?;
+		let acs_url = sp.get_acs_url(request.acs_url.as_ref())?;
+
+		Ok(Self {
+			id: request.id,
+			issuer: sp.entity_id.clone(),
+			acs_url,
+			relay_state,
+		})
+	}
+}
+
+fn decode_base64(value: &str) -> Result<Vec<u8>> {
+	let value: String = value.split_whitespace().collect();
+	openssl::base64::decode_block(&value).map_err(|_| AppErrorKind::InvalidSAMLRequest.into())
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+	use crate::CONFIG;
+
+	use std::io::Write;
+	use flate2::write::DeflateEncoder;
+	use flate2::Compression;
+
+	pub fn create_authn_request(acs_url: &str) -> String {
+		format!(r#"<samlp:AuthnRequest xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_request_id" Version="2.0" IssueInstant="2024-01-01T00:00:00Z" AssertionConsumerServiceURL="{}" ProtocolBinding="urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST">
+			<saml:Issuer>https://wiki.example.com/saml/metadata</saml:Issuer>
+			<samlp:NameIDPolicy Format="urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress" AllowCreate="true"/>
+		</samlp:AuthnRequest>"#, acs_url)
+	}
+
+	pub fn encode_redirect(xml: &str) -> String {
+		let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(xml.as_bytes()).unwrap();
+		openssl::base64::encode_block(&encoder.finish().unwrap())
+	}
+
+	#[actix_web::test]
+	async fn test_authn_request() {
+		ConfigFile::reload().await.unwrap();
+		let config = CONFIG.read().await;
+		let xml = create_authn_request("https://wiki.example.com/saml/acs");
+
+		let params = SAMLRequestParams {
+			saml_request: encode_redirect(&xml),
+			relay_state: Some("/page".to_string()),
+		};
+		let request = AuthnRequest::from_redirect(&config, &params).unwrap();
+		assert_eq!(request, AuthnRequest {
+			id: "_request_id".to_string(),
+			issuer: "https://wiki.example.com/saml/metadata".to_string(),
+			acs_url: "https://wiki.example.com/saml/acs".to_string(),
+			relay_state: Some("/page".to_string()),
+		});
+
+		let params = SAMLRequestParams {
+			saml_request: openssl::base64::encode_block(xml.as_bytes()),
+			relay_state: Some("/page".to_string()),
+		};
+		assert_eq!(AuthnRequest::from_post(&config, &params).unwrap(), request);
+
+		// Unregistered ACS URLs are rejected
+		let params = SAMLRequestParams {
+			saml_request: encode_redirect(&create_authn_request("https://evil.example.com/acs")),
+			relay_state: None,
+		};
+		assert!(AuthnRequest::from_redirect(&config, &params).is_err());
+
+		let params = SAMLRequestParams {
+			saml_request: "not base64!".to_string(),
+			relay_state: None,
+		};
+		assert!(AuthnRequest::from_redirect(&config, &params).is_err());
+	}
+}
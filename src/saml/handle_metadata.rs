@@ -0,0 +1,36 @@
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::saml::{get_entity_id, SAMLKeys, SAML_METADATA_NS, SAML_NAMEID_EMAIL, SAML_POST_BINDING, SAML_PROTOCOL_NS, SAML_REDIRECT_BINDING};
+use crate::CONFIG;
+
+/// The IdP metadata that the service providers are configured with (SAML Metadata section 2.4.3)
+#[get("/saml/metadata")]
+pub async fn metadata(keys: web::Data<SAMLKeys>) -> impl Responder {
+	let external_url = CONFIG.read().await.external_url.clone();
+	let sso_url = format!("{}/saml/sso", external_url);
+
+	let metadata = format!(concat!(
+		r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+		r#"<md:EntityDescriptor xmlns:md="{md_ns}" entityID="{entity_id}">"#,
+		r#"<md:IDPSSODescriptor WantAuthnRequestsSigned="false" protocolSupportEnumeration="{protocol_ns}">"#,
+		r#"<md:KeyDescriptor use="signing">"#,
+		r#"<ds:KeyInfo xmlns:ds="http://www.w3.org/2000/09/xmldsig#"><ds:X509Data><ds:X509Certificate>{certificate}</ds:X509Certificate></ds:X509Data></ds:KeyInfo>"#,
+		r#"</md:KeyDescriptor>"#,
+		r#"<md:NameIDFormat>{name_id_format}</md:NameIDFormat>"#,
+		r#"<md:SingleSignOnService Binding="{redirect_binding}" Location="{sso_url}"/>"#,
+		r#"<md:SingleSignOnService Binding="{post_binding}" Location="{sso_url}"/>"#,
+		r#"</md:IDPSSODescriptor>"#,
+		r#"</md:EntityDescriptor>"#),
+		md_ns = SAML_METADATA_NS,
+		entity_id = get_entity_id(&external_url),
+		protocol_ns = SAML_PROTOCOL_NS,
+		certificate = keys.certificate_base64(),
+		name_id_format = SAML_NAMEID_EMAIL,
+		redirect_binding = SAML_REDIRECT_BINDING,
+		post_binding = SAML_POST_BINDING,
+		sso_url = sso_url);
+
+	HttpResponse::Ok()
+		.content_type("application/samlmetadata+xml")
+		.body(metadata)
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+
+use actix_session::Session;
+use actix_web::http::header::ContentType;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::error::{AppErrorKind, Response};
+use crate::saml::authn_request::{AuthnRequest, SAMLRequestParams};
+use crate::saml::response::build_response;
+use crate::saml::service_provider::SAMLServiceProvider;
+use crate::saml::{get_entity_id, SAMLKeys};
+use crate::token::SessionToken;
+use crate::utils::get_partial;
+use crate::{CONFIG, SAML_AUTHN_REQUEST_COOKIE};
+
+/// Posts the signed response to the ACS URL of the service provider with an auto-submitted form
+async fn respond(keys: &SAMLKeys, authn_request: &AuthnRequest, user_session: &SessionToken) -> Response {
+	let config = CONFIG.read().await;
+	let sp = SAMLServiceProvider::from_entity_id(&config, &authn_request.issuer)?;
+	if !user_session.user.has_any_realm(&sp.realms) {
+		return Err(AppErrorKind::InvalidSAMLServiceProvider.into());
+	}
+
+	// The session code is a secret, the service provider only gets to tell sessions apart
+	let session_index = hex::encode(Sha256::digest(user_session.code.as_bytes()));
+	let idp_entity_id = get_entity_id(&config.external_url);
+	let response = build_response(keys, &idp_entity_id, authn_request, &user_session.user, &session_index)?;
+	drop(config);
+
+	info!("Sending SAML assertion for {} to {}", &user_session.user.email, &authn_request.issuer);
+
+	let mut post_data = BTreeMap::new();
+	post_data.insert("acs_url", authn_request.acs_url.clone());
+	post_data.insert("saml_response", openssl::base64::encode_block(response.as_bytes()));
+	if let Some(relay_state) = &authn_request.relay_state {
+		post_data.insert("relay_state", relay_state.clone());
+	}
+	let post_page = get_partial("saml_post", post_data)?;
+
+	Ok(HttpResponse::Ok()
+		.content_type(ContentType::html())
+		.body(post_page))
+}
+
+async fn sso(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, keys: web::Data<SAMLKeys>, authn_request: AuthnRequest) -> Response {
+	info!("Beginning SAML flow for {}", &authn_request.issuer);
+
+	let Ok(user_session) = SessionToken::from_session(&db, &session).await else {
+		// Picked up by `get_post_login_location` after the login
+		session.insert(SAML_AUTHN_REQUEST_COOKIE, authn_request)?;
+
+		let base_url = CONFIG.read().await.url_from_request(&req);
+		return Ok(HttpResponse::Found()
+			.append_header(("Location", format!("{}/login", base_url)))
+			.finish())
+	};
+
+	respond(&keys, &authn_request, &user_session).await
+}
+
+#[get("/saml/sso")]
+pub async fn sso_redirect(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, keys: web::Data<SAMLKeys>, params: web::Query<SAMLRequestParams>) -> Response {
+	let authn_request = AuthnRequest::from_redirect(&*CONFIG.read().await, &params)?;
+	sso(req, session, db, keys, authn_request).await
+}
+
+#[post("/saml/sso")]
+pub async fn sso_post(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, keys: web::Data<SAMLKeys>, params: web::Form<SAMLRequestParams>) -> Response {
+	let authn_request = AuthnRequest::from_post(&*CONFIG.read().await, &params)?;
+	sso(req, session, db, keys, authn_request).await
+}
+
+/// Where the user lands after logging in during a SAML flow
+#[get("/saml/resume")]
+pub async fn resume(session: Session, db: web::Data<reindeer::Db>, keys: web::Data<SAMLKeys>) -> Response {
+	let authn_request = session
+		.remove_as::<AuthnRequest>(SAML_AUTHN_REQUEST_COOKIE)
+		.and_then(Result::ok)
+		.ok_or(AppErrorKind::InvalidSAMLRequest)?;
+	let user_session = SessionToken::from_session(&db, &session).await?;
+
+	respond(&keys, &authn_request, &user_session).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::saml::authn_request::tests::{create_authn_request, encode_redirect};
+	use crate::saml::tests::create_keys;
+	use crate::token::MagicLinkToken;
+	use crate::utils::tests::*;
+
+	use actix_session::storage::CookieSessionStore;
+	use actix_session::SessionMiddleware;
+	use actix_web::cookie::{Cookie, Key};
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+
+	#[actix_web::test]
+	async fn test_saml_sso() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let secret = Key::from(&[0; 64]);
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(create_keys()))
+				.service(crate::handle_login_link::login_link)
+				.service(sso_redirect)
+				.service(sso_post)
+				.service(resume)
+				.wrap(
+					SessionMiddleware::builder(
+						CookieSessionStore::default(),
+						secret
+					)
+					.build())
+		)
+		.await;
+
+		let saml_request = urlencoding::encode(&encode_redirect(&create_authn_request("https://wiki.example.com/saml/acs"))).to_string();
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/saml/sso?SAMLRequest={}&RelayState=%2Fpage", saml_request).as_str())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert!(resp.headers().get("Location").unwrap().to_str().unwrap().ends_with("/login"));
+		let cookie = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
+
+		// After the login the user is sent back to finish the SAML flow
+		let token = MagicLinkToken::new(db, user, None, None).await.unwrap();
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/login/{}", token.code).as_str())
+			.cookie(cookie)
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::FOUND);
+		assert_eq!(resp.headers().get("Location").unwrap().to_str().unwrap(), "/saml/resume");
+		let cookie: Cookie<'_> = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
+
+		let req = actix_test::TestRequest::get()
+			.uri("/saml/resume")
+			.cookie(cookie.clone())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body = actix_test::read_body(resp).await;
+		let body_str = std::str::from_utf8(&body).unwrap();
+		assert!(body_str.contains(r#"action="https://wiki.example.com/saml/acs""#));
+		assert!(body_str.contains(r#"name="RelayState" value="/page""#));
+
+		let html_parse = scraper::Html::parse_document(body_str);
+		let saml_response = html_parse
+			.select(&scraper::Selector::parse("input[name=SAMLResponse]").unwrap())
+			.next()
+			.unwrap()
+			.value()
+			.attr("value")
+			.unwrap();
+		let saml_response = String::from_utf8(openssl::base64::decode_block(saml_response).unwrap()).unwrap();
+		assert!(saml_response.contains(r#"InResponseTo="_request_id""#));
+		assert!(saml_response.contains("<saml:AttributeValue>valid@example.com</saml:AttributeValue>"));
+
+		// Already logged in users get the response right away, over the POST binding as well
+		let req = actix_test::TestRequest::post()
+			.uri("/saml/sso")
+			.cookie(cookie)
+			.set_form(&SAMLRequestParams {
+				saml_request: openssl::base64::encode_block(create_authn_request("https://wiki.example.com/saml/acs").as_bytes()),
+				relay_state: None,
+			})
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+	}
+}
@@ -0,0 +1,101 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::{X509NameBuilder, X509};
+use reindeer::{Db, Entity};
+
+use crate::config::{ConfigKV, ConfigKeys};
+
+pub mod authn_request;
+pub mod handle_metadata;
+pub mod handle_sso;
+pub mod response;
+pub mod service_provider;
+
+pub const SAML_ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+pub const SAML_PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+pub const SAML_METADATA_NS: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+pub const SAML_REDIRECT_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect";
+pub const SAML_POST_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+pub const SAML_NAMEID_EMAIL: &str = "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress";
+
+/// The key that signs the assertions along with its self-signed certificate,
+/// which is what the service providers pin from the metadata
+#[derive(Clone)]
+pub struct SAMLKeys {
+	pub key: PKey<Private>,
+	pub certificate: X509,
+}
+
+impl SAMLKeys {
+	/// The base64 DER certificate, as it goes in `X509Certificate` elements
+	pub fn certificate_base64(&self) -> String {
+		let der = self.certificate.to_der().expect("Failed to encode the SAML certificate");
+		openssl::base64::encode_block(&der)
+	}
+}
+
+/// The entity ID of the IdP, which is also where its metadata lives
+pub fn get_entity_id(external_url: &str) -> String {
+	format!("{}/saml/metadata", external_url)
+}
+
+fn generate_certificate(key: &PKey<Private>, title: &str) -> X509 {
+	let mut name = X509NameBuilder::new().expect("Failed to create the SAML certificate name");
+	name.append_entry_by_text("CN", title).expect("Failed to set the SAML certificate name");
+	let name = name.build();
+
+	let mut serial = BigNum::new().expect("Failed to create the SAML certificate serial");
+	serial.rand(128, MsbOption::MAYBE_ZERO, false).expect("Failed to generate the SAML certificate serial");
+
+	let mut builder = X509::builder().expect("Failed to create the SAML certificate");
+	builder.set_version(2).expect("Failed to set the SAML certificate version");
+	builder.set_serial_number(&serial.to_asn1_integer().expect("Failed to convert the SAML certificate serial")).expect("Failed to set the SAML certificate serial");
+	builder.set_subject_name(&name).expect("Failed to set the SAML certificate subject");
+	builder.set_issuer_name(&name).expect("Failed to set the SAML certificate issuer");
+	builder.set_pubkey(key).expect("Failed to set the SAML certificate key");
+	builder.set_not_before(&Asn1Time::days_from_now(0).expect("Failed to create the SAML certificate validity")).expect("Failed to set the SAML certificate validity");
+	builder.set_not_after(&Asn1Time::days_from_now(3650).expect("Failed to create the SAML certificate validity")).expect("Failed to set the SAML certificate validity");
+	builder.sign(key, MessageDigest::sha256()).expect("Failed to sign the SAML certificate");
+
+	builder.build()
+}
+
+pub async fn init(db: &Db) -> SAMLKeys {
+	if let (Ok(Some(key)), Ok(Some(certificate))) = (ConfigKV::get(&ConfigKeys::SAMLKey, db), ConfigKV::get(&ConfigKeys::SAMLCertificate, db)) {
+		let key = key.value.expect("Failed to load SAML key from database");
+		let certificate = certificate.value.expect("Failed to load SAML certificate from database");
+
+		SAMLKeys {
+			key: PKey::private_key_from_pem(key.as_bytes()).expect("Failed to load SAML key from database"),
+			certificate: X509::from_pem(certificate.as_bytes()).expect("Failed to load SAML certificate from database"),
+		}
+	} else {
+		log::warn!("Generating SAML key for RSA 2048 and its certificate");
+		let title = crate::CONFIG.read().await.title.clone();
+		let key = PKey::from_rsa(Rsa::generate(2048).expect("Failed to generate RSA 2048 key")).expect("Failed to generate RSA 2048 key");
+		let certificate = generate_certificate(&key, &title);
+
+		let key_pem = String::from_utf8(key.private_key_to_pem_pkcs8().expect("Failed to convert SAML key to PEM")).expect("SAML key PEM is not UTF-8");
+		let certificate_pem = String::from_utf8(certificate.to_pem().expect("Failed to convert SAML certificate to PEM")).expect("SAML certificate PEM is not UTF-8");
+		ConfigKV::set(ConfigKeys::SAMLKey, Some(key_pem), db).expect("Unable to save SAML key in the database");
+		ConfigKV::set(ConfigKeys::SAMLCertificate, Some(certificate_pem), db).expect("Unable to save SAML certificate in the database");
+
+		SAMLKeys { key, certificate }
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+
+	/// Generating a key on every test is slow enough, so a smaller one is used
+	pub fn create_keys() -> SAMLKeys {
+		let key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+		let certificate = generate_certificate(&key, "MagicEntry");
+
+		SAMLKeys { key, certificate }
+	}
+}
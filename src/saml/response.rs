@@ -0,0 +1,243 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use openssl::hash::MessageDigest;
+use openssl::sign::Signer;
+use sha2::{Digest, Sha256};
+
+use crate::error::Result;
+use crate::saml::authn_request::AuthnRequest;
+use crate::saml::{SAMLKeys, SAML_ASSERTION_NS, SAML_NAMEID_EMAIL, SAML_PROTOCOL_NS};
+use crate::user::User;
+use crate::utils::random_string;
+
+const XMLDSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+
+/// How long the SP has to consume the assertion
+pub const SAML_ASSERTION_DURATION: i64 = 5;
+
+/// Escapes text content the way Exclusive XML Canonicalization outputs it
+fn escape_text(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('\r', "&#xD;")
+}
+
+/// Escapes attribute values the way Exclusive XML Canonicalization outputs them
+fn escape_attr(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('"', "&quot;")
+		.replace('\t', "&#x9;")
+		.replace('\n', "&#xA;")
+		.replace('\r', "&#xD;")
+}
+
+fn format_instant(instant: NaiveDateTime) -> String {
+	instant.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn attribute(name: &str, values: &[&str]) -> String {
+	let values = values
+		.iter()
+		.map(|v| format!("<saml:AttributeValue>{}</saml:AttributeValue>", escape_text(v)))
+		.collect::<String>();
+
+	format!(r#"<saml:Attribute Name="{}" NameFormat="urn:oasis:names:tc:SAML:2.0:attrname-format:basic">{}</saml:Attribute>"#, name, values)
+}
+
+/// Signs the assertion with an enveloped signature right after its `Issuer`, as the schema requires.
+///
+/// There is no XML library involved: the assertion is already written in its
+/// Exclusive XML Canonical form (sorted attributes, no self-closing tags, no whitespace
+/// between the elements, namespaces declared where they're used), so it can be digested as is.
+fn sign_assertion(keys: &SAMLKeys, id: &str, head: &str, body: &str) -> Result<String> {
+	let digest = openssl::base64::encode_block(&Sha256::digest(format!("{}{}", head, body).as_bytes()));
+
+	let signed_info = format!(concat!(
+		r#"<ds:SignedInfo xmlns:ds="{ns}">"#,
+		r#"<ds:CanonicalizationMethod Algorithm="{c14n}"></ds:CanonicalizationMethod>"#,
+		r#"<ds:SignatureMethod Algorithm="http://www.w3.org/2001/04/xmldsig-more#rsa-sha256"></ds:SignatureMethod>"#,
+		r##"<ds:Reference URI="#{id}">"##,
+		r#"<ds:Transforms>"#,
+		r#"<ds:Transform Algorithm="http://www.w3.org/2000/09/xmldsig#enveloped-signature"></ds:Transform>"#,
+		r#"<ds:Transform Algorithm="{c14n}"></ds:Transform>"#,
+		r#"</ds:Transforms>"#,
+		r#"<ds:DigestMethod Algorithm="http://www.w3.org/2001/04/xmlenc#sha256"></ds:DigestMethod>"#,
+		r#"<ds:DigestValue>{digest}</ds:DigestValue>"#,
+		r#"</ds:Reference>"#,
+		r#"</ds:SignedInfo>"#),
+		ns = XMLDSIG_NS,
+		c14n = EXC_C14N,
+		id = id,
+		digest = digest);
+
+	let mut signer = Signer::new(MessageDigest::sha256(), &keys.key)?;
+	signer.update(signed_info.as_bytes())?;
+	let signature_value = openssl::base64::encode_block(&signer.sign_to_vec()?);
+
+	// Inside the Signature the namespace is inherited, so SignedInfo doesn't redeclare it
+	let signed_info = signed_info.replacen(&format!(r#" xmlns:ds="{}""#, XMLDSIG_NS), "", 1);
+	let signature = format!(
+		r#"<ds:Signature xmlns:ds="{}">{}<ds:SignatureValue>{}</ds:SignatureValue><ds:KeyInfo><ds:X509Data><ds:X509Certificate>{}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></ds:Signature>"#,
+		XMLDSIG_NS,
+		signed_info,
+		signature_value,
+		keys.certificate_base64());
+
+	Ok(format!("{}{}{}", head, signature, body))
+}
+
+/// Builds the signed assertion about the user for the service provider
+pub fn build_assertion(keys: &SAMLKeys, idp_entity_id: &str, authn_request: &AuthnRequest, user: &User, session_index: &str) -> Result<String> {
+	let id = format!("_{}", random_string());
+	build_assertion_at(keys, &id, Utc::now().naive_utc(), idp_entity_id, authn_request, user, session_index)
+}
+
+/// Builds the signed assertion with the given ID, issued at `now`
+fn build_assertion_at(keys: &SAMLKeys, id: &str, now: NaiveDateTime, idp_entity_id: &str, authn_request: &AuthnRequest, user: &User, session_index: &str) -> Result<String> {
+	let issue_instant = format_instant(now);
+	let not_on_or_after = format_instant(now + Duration::try_minutes(SAML_ASSERTION_DURATION).unwrap_or_default());
+
+	let head = format!(
+		r#"<saml:Assertion xmlns:saml="{}" ID="{}" IssueInstant="{}" Version="2.0"><saml:Issuer>{}</saml:Issuer>"#,
+		SAML_ASSERTION_NS,
+		id,
+		issue_instant,
+		escape_text(idp_entity_id));
+
+	let attributes = [
+		attribute("email", &[&user.email]),
+		attribute("name", &[&user.name]),
+		attribute("realms", &user.realms.iter().map(String::as_str).collect::<Vec<_>>()),
+		attribute("username", &[&user.username]),
+	].concat();
+
+	let body = format!(concat!(
+		r#"<saml:Subject>"#,
+		r#"<saml:NameID Format="{name_id_format}">{email}</saml:NameID>"#,
+		r#"<saml:SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer">"#,
+		r#"<saml:SubjectConfirmationData InResponseTo="{in_response_to}" NotOnOrAfter="{not_on_or_after}" Recipient="{acs_url}"></saml:SubjectConfirmationData>"#,
+		r#"</saml:SubjectConfirmation>"#,
+		r#"</saml:Subject>"#,
+		r#"<saml:Conditions NotBefore="{issue_instant}" NotOnOrAfter="{not_on_or_after}">"#,
+		r#"<saml:AudienceRestriction><saml:Audience>{audience}</saml:Audience></saml:AudienceRestriction>"#,
+		r#"</saml:Conditions>"#,
+		r#"<saml:AuthnStatement AuthnInstant="{issue_instant}" SessionIndex="{session_index}">"#,
+		r#"<saml:AuthnContext><saml:AuthnContextClassRef>urn:oasis:names:tc:SAML:2.0:ac:classes:unspecified</saml:AuthnContextClassRef></saml:AuthnContext>"#,
+		r#"</saml:AuthnStatement>"#,
+		r#"<saml:AttributeStatement>{attributes}</saml:AttributeStatement>"#,
+		r#"</saml:Assertion>"#),
+		name_id_format = SAML_NAMEID_EMAIL,
+		email = escape_text(&user.email),
+		in_response_to = escape_attr(&authn_request.id),
+		not_on_or_after = not_on_or_after,
+		acs_url = escape_attr(&authn_request.acs_url),
+		issue_instant = issue_instant,
+		audience = escape_text(&authn_request.issuer),
+		session_index = escape_attr(session_index),
+		attributes = attributes);
+
+	sign_assertion(keys, id, &head, &body)
+}
+
+/// Builds the `samlp:Response` carrying the signed assertion, ready to be base64 encoded and posted
+pub fn build_response(keys: &SAMLKeys, idp_entity_id: &str, authn_request: &AuthnRequest, user: &User, session_index: &str) -> Result<String> {
+	let assertion = build_assertion(keys, idp_entity_id, authn_request, user, session_index)?;
+
+	Ok(format!(
+		r#"<samlp:Response xmlns:samlp="{}" xmlns:saml="{}" Destination="{}" ID="_{}" InResponseTo="{}" IssueInstant="{}" Version="2.0"><saml:Issuer>{}</saml:Issuer><samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"></samlp:StatusCode></samlp:Status>{}</samlp:Response>"#,
+		SAML_PROTOCOL_NS,
+		SAML_ASSERTION_NS,
+		escape_attr(&authn_request.acs_url),
+		random_string(),
+		escape_attr(&authn_request.id),
+		format_instant(Utc::now().naive_utc()),
+		escape_text(idp_entity_id),
+		assertion))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::saml::tests::create_keys;
+	use crate::utils::tests::*;
+
+	use openssl::pkey::PKey;
+	use openssl::sign::Verifier;
+	use openssl::x509::X509;
+
+	fn between<'a>(value: &'a str, start: &str, end: &str) -> &'a str {
+		let from = value.find(start).unwrap() + start.len();
+		let to = from + value[from..].find(end).unwrap();
+		&value[from..to]
+	}
+
+	#[actix_web::test]
+	async fn test_assertion_signature() {
+		let keys = create_keys();
+		let user = get_valid_user().await;
+		let authn_request = AuthnRequest {
+			id: "_request_id".to_string(),
+			issuer: "https://wiki.example.com/saml/metadata".to_string(),
+			acs_url: "https://wiki.example.com/saml/acs?a=1&b=2".to_string(),
+			relay_state: None,
+		};
+
+		let response = build_response(&keys, "http://localhost:8080/saml/metadata", &authn_request, &user, "session").unwrap();
+		assert!(response.contains(r#"Destination="https://wiki.example.com/saml/acs?a=1&amp;b=2""#));
+		assert!(response.contains("<saml:NameID Format=\"urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress\">valid@example.com</saml:NameID>"));
+		assert!(response.contains("<saml:Audience>https://wiki.example.com/saml/metadata</saml:Audience>"));
+
+		// The digest covers the assertion without the enveloped signature
+		let assertion = between(&response, "</samlp:Status>", "</samlp:Response>");
+		let signature = format!("<ds:Signature{}</ds:Signature>", between(assertion, "<ds:Signature", "</ds:Signature>"));
+		let unsigned = assertion.replace(&signature, "");
+		let digest = openssl::base64::encode_block(&Sha256::digest(unsigned.as_bytes()));
+		assert_eq!(between(&signature, "<ds:DigestValue>", "</ds:DigestValue>"), digest);
+
+		// The signature covers the canonical SignedInfo, which declares the namespace itself
+		let signed_info = format!(
+			r#"<ds:SignedInfo xmlns:ds="{}">{}</ds:SignedInfo>"#,
+			XMLDSIG_NS,
+			between(&signature, "<ds:SignedInfo>", "</ds:SignedInfo>"));
+		let signature_value = openssl::base64::decode_block(between(&signature, "<ds:SignatureValue>", "</ds:SignatureValue>")).unwrap();
+		let public_key = keys.certificate.public_key().unwrap();
+		let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+		verifier.update(signed_info.as_bytes()).unwrap();
+		assert!(verifier.verify(&signature_value).unwrap());
+	}
+
+	/// An assertion signed with the checked-in key, so that it doesn't change between runs
+	fn fixture_assertion() -> String {
+		let keys = SAMLKeys {
+			key: PKey::private_key_from_pem(include_bytes!("../../tests/saml/key.pem")).unwrap(),
+			certificate: X509::from_pem(include_bytes!("../../tests/saml/certificate.pem")).unwrap(),
+		};
+		let user = User {
+			username: "valid".to_string(),
+			realms: vec!["example".to_string(), "admins".to_string()],
+			email: "valid@example.com".to_string(),
+			name: "Valid <User> & \"Co\"".to_string(),
+		};
+		let authn_request = AuthnRequest {
+			id: "_request_id".to_string(),
+			issuer: "https://wiki.example.com/saml/metadata".to_string(),
+			acs_url: "https://wiki.example.com/saml/acs?a=1&b=2".to_string(),
+			relay_state: None,
+		};
+		let now = NaiveDateTime::parse_from_str("2024-01-02T03:04:05", "%Y-%m-%dT%H:%M:%S").unwrap();
+
+		build_assertion_at(&keys, "_fixture", now, "http://localhost:8080/saml/metadata", &authn_request, &user, "session").unwrap()
+	}
+
+	/// tests/saml/assertion.xml is verified with libxml2's Exclusive C14N and the openssl CLI
+	/// instead of the code above by tests/saml/verify.sh, which the Test workflow runs.
+	/// When this comparison changes, the fixture has to be regenerated and verified again.
+	#[test]
+	fn test_assertion_fixture() {
+		assert_eq!(fixture_assertion(), include_str!("../../tests/saml/assertion.xml"));
+	}
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigFile;
+use crate::error::{AppErrorKind, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SAMLServiceProvider {
+	/// The `Issuer` of the AuthnRequests and the `Audience` of the assertions
+	pub entity_id: String,
+	/// How the service provider is presented to the user
+	#[serde(default)]
+	pub name: Option<String>,
+	/// The Assertion Consumer Service URLs that the responses may be posted to,
+	/// the first one is the default
+	pub acs_urls: Vec<String>,
+	pub realms: Vec<String>,
+}
+
+impl SAMLServiceProvider {
+	pub fn from_entity_id<'a>(config: &'a ConfigFile, entity_id: &str) -> Result<&'a Self> {
+		config.saml_service_providers
+			.iter()
+			.find(|sp| sp.entity_id == entity_id)
+			.ok_or(AppErrorKind::InvalidSAMLServiceProvider.into())
+	}
+
+	/// The ACS URL has to be registered, as the assertion is as good as a password for it
+	pub fn get_acs_url(&self, requested: Option<&String>) -> Result<String> {
+		match requested {
+			Some(acs_url) if self.acs_urls.contains(acs_url) => Ok(acs_url.clone()),
+			Some(_) => Err(AppErrorKind::InvalidSAMLAssertionConsumerService.into()),
+			None => self.acs_urls.first().cloned().ok_or(AppErrorKind::InvalidSAMLAssertionConsumerService.into()),
+		}
+	}
+}
@@ -0,0 +1,14 @@
+use actix_web::{get, web, HttpResponse};
+use ssh_key::PrivateKey;
+
+use crate::error::Response;
+
+/// The CA public key for the `TrustedUserCAKeys` of the hosts
+#[get("/ssh/ca.pub")]
+pub async fn ca(ca_key: web::Data<PrivateKey>) -> Response {
+	let public_key = ca_key.public_key().to_openssh()?;
+
+	Ok(HttpResponse::Ok()
+		.content_type("text/plain; charset=utf-8")
+		.body(format!("{}\n", public_key)))
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use actix_session::Session;
+use actix_web::http::header::ContentType;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use log::info;
+use serde::{Deserialize, Serialize};
+use ssh_key::PrivateKey;
+
+use crate::error::{AppErrorKind, Response};
+use crate::oidc::handle_token::BearerTokenMetadata;
+use crate::oidc::handle_userinfo::token_from_request;
+use crate::token::SessionToken;
+use crate::utils::get_partial;
+use crate::CONFIG;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SignRequest {
+	/// The contents of the `.pub` file, e.g. `ssh-ed25519 AAAA... user@host`
+	pub public_key: String,
+}
+
+fn sign_page(email: &str, certificate: Option<String>) -> Response {
+	let mut page_data = BTreeMap::new();
+	page_data.insert("email", email.to_string());
+	if let Some(certificate) = certificate {
+		page_data.insert("certificate", certificate);
+	}
+	let page = get_partial("ssh", page_data)?;
+
+	Ok(HttpResponse::Ok()
+		.content_type(ContentType::html())
+		.body(page))
+}
+
+#[get("/ssh")]
+pub async fn sign_get(session: Session, db: web::Data<reindeer::Db>) -> Response {
+	let user_session = SessionToken::from_session(&db, &session).await?;
+	sign_page(&user_session.user.email, None)
+}
+
+/// Signs the public key of the logged in user. Browsers are identified by their session
+/// and get the page back, CLIs send an OIDC access token requested with this endpoint
+/// as its `resource` and get just the certificate, e.g.
+/// `curl -H "Authorization: Bearer $TOKEN" --data-urlencode public_key@id_ed25519.pub .../ssh/sign > id_ed25519-cert.pub`
+#[post("/ssh/sign")]
+pub async fn sign_post(req: HttpRequest, session: Session, db: web::Data<reindeer::Db>, ca_key: web::Data<PrivateKey>, form: web::Form<SignRequest>) -> Response {
+	let (user, from_browser) = if let Ok(user_session) = SessionToken::from_session(&db, &session).await {
+		(user_session.user, true)
+	} else {
+		let token = token_from_request(&db, &req).await?;
+
+		// Only tokens restricted to the CA (RFC 8707) are accepted, the ones of
+		// the other clients can't be turned into SSH certificates
+		let metadata = BearerTokenMetadata::from_token(&token)?.ok_or(AppErrorKind::InvalidTokenAudience)?;
+		let sign_url = format!("{}/ssh/sign", CONFIG.read().await.url_from_request(&req));
+		if !metadata.audience.contains(&sign_url) {
+			return Err(AppErrorKind::InvalidTokenAudience.into());
+		}
+
+		(token.user, false)
+	};
+
+	let duration = CONFIG.read().await.ssh_ca_certificate_duration;
+	let certificate = crate::ssh_ca::sign(&ca_key, &user, &form.public_key, duration)?.to_openssh()?;
+	info!("Signed SSH certificate for {}", &user.email);
+
+	if from_browser {
+		sign_page(&user.email, Some(certificate))
+	} else {
+		Ok(HttpResponse::Ok()
+			.content_type("text/plain; charset=utf-8")
+			.body(format!("{}\n", certificate)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::token::{MagicLinkToken, OIDCBearerToken};
+	use crate::utils::tests::*;
+
+	use actix_session::storage::CookieSessionStore;
+	use actix_session::SessionMiddleware;
+	use actix_web::cookie::Key;
+	use actix_web::http::StatusCode;
+	use actix_web::{test as actix_test, App};
+	use ssh_key::certificate::Certificate;
+	use ssh_key::Algorithm;
+
+	#[actix_web::test]
+	async fn test_ssh_sign_endpoint() {
+		let db = &db_connect().await;
+		let user = get_valid_user().await;
+		let ca_key = PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519).unwrap();
+		let ca_fingerprint = ca_key.fingerprint(Default::default());
+		let public_key = PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519).unwrap().public_key().to_openssh().unwrap();
+		let mut app = actix_test::init_service(
+			App::new()
+				.app_data(web::Data::new(db.clone()))
+				.app_data(web::Data::new(ca_key))
+				.service(crate::handle_login_link::login_link)
+				.service(sign_get)
+				.service(sign_post)
+				.wrap(SessionMiddleware::new(CookieSessionStore::default(), Key::from(&[0; 64])))
+		)
+		.await;
+
+		let new_token = |audience: &[&str]| {
+			let metadata = BearerTokenMetadata {
+				client_id: "my_client".to_string(),
+				audience: audience.iter().map(|a| a.to_string()).collect(),
+				jkt: None,
+				scope: None,
+			};
+			OIDCBearerToken::new(db, user.clone(), None, Some(String::try_from(&metadata).unwrap()))
+		};
+
+		// Access tokens that aren't restricted to the CA can't be used
+		for bearer in [
+			OIDCBearerToken::new(db, user.clone(), None, None).await.unwrap(),
+			new_token(&[]).await.unwrap(),
+			new_token(&["https://api.example.com"]).await.unwrap(),
+		] {
+			let req = actix_test::TestRequest::post()
+				.uri("/ssh/sign")
+				.insert_header(("Authorization", format!("Bearer {}", bearer.code)))
+				.set_form(&SignRequest { public_key: public_key.clone() })
+				.to_request();
+			let resp = actix_test::call_service(&mut app, req).await;
+			assert_ne!(resp.status(), StatusCode::OK);
+		}
+
+		// CLIs authenticate with an access token
+		let bearer = new_token(&["http://localhost:8080/ssh/sign"]).await.unwrap();
+		let req = actix_test::TestRequest::post()
+			.uri("/ssh/sign")
+			.insert_header(("Authorization", format!("Bearer {}", bearer.code)))
+			.set_form(&SignRequest { public_key: public_key.clone() })
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body = actix_test::read_body(resp).await;
+		let certificate = Certificate::from_openssh(std::str::from_utf8(&body).unwrap().trim()).unwrap();
+		assert!(certificate.validate(&[ca_fingerprint]).is_ok());
+		assert_eq!(certificate.valid_principals(), &["valid".to_string(), "realm:example".to_string()]);
+
+		// Browsers use their session and get the certificate on the page
+		let token = MagicLinkToken::new(db, user, None, None).await.unwrap();
+		let req = actix_test::TestRequest::get()
+			.uri(format!("/login/{}", token.code).as_str())
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		let cookie = resp.response().cookies().find(|c| c.name() == "id").unwrap().into_owned();
+
+		let req = actix_test::TestRequest::post()
+			.uri("/ssh/sign")
+			.cookie(cookie)
+			.set_form(&SignRequest { public_key })
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body = actix_test::read_body(resp).await;
+		assert!(std::str::from_utf8(&body).unwrap().contains("ssh-ed25519-cert-v01@openssh.com "));
+
+		let req = actix_test::TestRequest::post()
+			.uri("/ssh/sign")
+			.set_form(&SignRequest { public_key: "ssh-ed25519 AAAA".to_string() })
+			.to_request();
+		let resp = actix_test::call_service(&mut app, req).await;
+		assert_ne!(resp.status(), StatusCode::OK);
+	}
+}
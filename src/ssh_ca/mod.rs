@@ -0,0 +1,92 @@
+use chrono::Utc;
+use rand::RngCore;
+use reindeer::{Db, Entity};
+use ssh_key::certificate::{Builder, CertType, Certificate};
+use ssh_key::{Algorithm, LineEnding, PrivateKey, PublicKey};
+
+use crate::config::{ConfigKV, ConfigKeys};
+use crate::error::{AppErrorKind, Result};
+use crate::user::User;
+
+pub mod handle_ca;
+pub mod handle_sign;
+
+/// The same permissions that `ssh-keygen` gives to user certificates by default
+const EXTENSIONS: [&str; 5] = [
+	"permit-X11-forwarding",
+	"permit-agent-forwarding",
+	"permit-port-forwarding",
+	"permit-pty",
+	"permit-user-rc",
+];
+
+pub async fn init(db: &Db) -> PrivateKey {
+	if let Ok(Some(key)) = ConfigKV::get(&ConfigKeys::SshCaKey, db) {
+		let key = key.value.expect("Failed to load SSH CA key from database");
+		PrivateKey::from_openssh(key).expect("Failed to load SSH CA key from database")
+	} else {
+		log::warn!("Generating SSH CA key for Ed25519");
+		let key = PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519).expect("Failed to generate Ed25519 key");
+		let key_pem = key.to_openssh(LineEnding::LF).expect("Failed to convert SSH CA key to OpenSSH format");
+		ConfigKV::set(ConfigKeys::SshCaKey, Some(key_pem.to_string()), db).expect("Unable to save SSH CA key in the database");
+
+		key
+	}
+}
+
+/// The prefix of the realm principals, so that a realm can't be mistaken for a login
+/// (e.g. `root`) by hosts without an `AuthorizedPrincipalsFile`
+pub const REALM_PRINCIPAL_PREFIX: &str = "realm:";
+
+/// The user can log in as their username, or as any login whose `AuthorizedPrincipalsFile`
+/// lists one of their realms as `realm:<name>`
+pub fn get_principals(user: &User) -> Vec<String> {
+	std::iter::once(user.username.clone())
+		.chain(user.realms.iter().map(|realm| format!("{}{}", REALM_PRINCIPAL_PREFIX, realm)))
+		.collect()
+}
+
+/// Signs the public key of the user into a user certificate valid for the given duration
+pub fn sign(ca_key: &PrivateKey, user: &User, public_key: &str, duration: chrono::Duration) -> Result<Certificate> {
+	let public_key = PublicKey::from_openssh(public_key.trim()).map_err(|_| AppErrorKind::InvalidSSHPublicKey)?;
+	let now = Utc::now().timestamp() as u64;
+	let valid_before = now + duration.num_seconds() as u64;
+
+	let mut builder = Builder::new_with_random_nonce(&mut rand::rngs::OsRng, public_key.key_data().clone(), now, valid_before)?;
+	builder.serial(rand::rngs::OsRng.next_u64())?;
+	builder.cert_type(CertType::User)?;
+	builder.key_id(user.email.clone())?;
+	for principal in get_principals(user) {
+		builder.valid_principal(principal)?;
+	}
+	for extension in EXTENSIONS {
+		builder.extension(extension, "")?;
+	}
+	builder.comment(user.email.clone())?;
+
+	Ok(builder.sign(ca_key)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::tests::*;
+
+	#[actix_web::test]
+	async fn test_ssh_sign() {
+		let user = get_valid_user().await;
+		let ca_key = PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519).unwrap();
+		let user_key = PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519).unwrap();
+		let public_key = user_key.public_key().to_openssh().unwrap();
+
+		let certificate = sign(&ca_key, &user, &public_key, chrono::Duration::try_hours(1).unwrap()).unwrap();
+		assert_eq!(certificate.cert_type(), CertType::User);
+		assert_eq!(certificate.key_id(), "valid@example.com");
+		assert_eq!(certificate.valid_principals(), &["valid".to_string(), "realm:example".to_string()]);
+		assert_eq!(certificate.public_key(), user_key.public_key().key_data());
+		assert!(certificate.validate(&[ca_key.fingerprint(Default::default())]).is_ok());
+		assert!(certificate.to_openssh().unwrap().starts_with("ssh-ed25519-cert-v01@openssh.com "));
+
+		assert!(sign(&ca_key, &user, "not a key", chrono::Duration::try_hours(1).unwrap()).is_err());
+	}
+}
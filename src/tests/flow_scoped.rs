@@ -2,6 +2,7 @@ use actix_session::storage::CookieSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::test::{call_service, TestRequest};
 use crate::utils::tests::db_connect;
+use jwt_simple::algorithms::RS256KeyPair;
 use crate::*;
 
 use actix_web::cookie::{Cookie, Key};
@@ -16,6 +17,7 @@ async fn test_global_login() {
 	let mut app = actix_test::init_service(
 		App::new()
 			.app_data(web::Data::new(db))
+			.app_data(web::Data::new(RS256KeyPair::generate(2048).unwrap().with_key_id("default")))
 			.app_data(web::Data::new(Some(email_stub.clone())))
 			.app_data(web::Data::new(None::<reqwest::Client>))
 			.service(handle_index::index)
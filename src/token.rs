@@ -10,7 +10,7 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::user::User;
-use crate::utils::{get_request_origin, random_string };
+use crate::utils::random_string;
 use crate::{PROXIED_COOKIE, SCOPED_SESSION_COOKIE, SESSION_COOKIE};
 use crate::error::{AppErrorKind, Result};
 
@@ -136,7 +136,35 @@ impl<K: TokenKindType> Token<K> {
 		Ok(token)
 	}
 
+	/// Same as `from_code` but ephemeral tokens are left in place,
+	/// for the checks that have to pass before the token is spent
+	pub async fn peek(db: &Db, code: &String) -> Result<Self> {
+		let token = Self::get(code, db)?.ok_or(AppErrorKind::TokenNotFound)?;
+
+		if token.is_expired(db).await? {
+			token.delete(db).await?;
+			return Err(AppErrorKind::TokenNotFound.into());
+		}
+
+		Ok(token)
+	}
+
 	pub async fn new(db: &Db, user: User, bound_to: Option<String>, metadata: Option<String>) -> Result<Self> {
+		Self::new_with_duration(db, user, bound_to, metadata, None).await
+	}
+
+	/// Same as `new` but with a custom duration instead of the one of the token kind.
+	/// A bound token still can't outlive its parent.
+	pub async fn new_with_duration(db: &Db, user: User, bound_to: Option<String>, metadata: Option<String>, duration: Option<chrono::Duration>) -> Result<Self> {
+		let own_expiry = if let Some(duration) = duration {
+			Utc::now()
+				.naive_utc()
+				.checked_add_signed(duration)
+				.ok_or(AppErrorKind::InvalidDuration)?
+		} else {
+			K::get_expiry().await
+		};
+
 		let expires_at = if let Some(bound_code) = &bound_to {
 			let bound_token: Token<K::BoundType> = Token::from_code(db, &bound_code).await?;
 
@@ -144,9 +172,14 @@ impl<K: TokenKindType> Token<K> {
 				return Err(AppErrorKind::InvalidParentToken.into());
 			}
 
-			bound_token.expires_at
+			// Without a custom duration the token lives as long as its parent, like before
+			if duration.is_some() {
+				bound_token.expires_at.min(own_expiry)
+			} else {
+				bound_token.expires_at
+			}
 		} else {
-			K::get_expiry().await
+			own_expiry
 		};
 
 		let token = Self {
@@ -163,6 +196,11 @@ impl<K: TokenKindType> Token<K> {
 		Ok(token)
 	}
 
+	/// The seconds until the token expires, as used in `expires_in`
+	pub fn expires_in(&self) -> i64 {
+		(self.expires_at - Utc::now().naive_utc()).num_seconds()
+	}
+
 	pub async fn delete(&self, db: &Db) -> Result<()> {
 		let now = Utc::now().naive_utc();
 		let code = self.code.clone();
@@ -187,6 +225,8 @@ token_kind! {
 	ScopedSessionToken(duration = crate::CONFIG.read().await.session_duration, ephemeral = false, bound_type = SessionToken),
 	OIDCCodeToken(duration = crate::CONFIG.read().await.oidc_code_duration, ephemeral = true, bound_type = SessionToken),
 	OIDCBearerToken(duration = crate::CONFIG.read().await.session_duration, ephemeral = false, bound_type = Self),
+	OIDCRefreshToken(duration = crate::CONFIG.read().await.session_duration, ephemeral = true, bound_type = SessionToken),
+	CASServiceTicketToken(duration = crate::CONFIG.read().await.oidc_code_duration, ephemeral = true, bound_type = SessionToken),
 	WebauthnToken(duration = crate::CONFIG.read().await.oidc_code_duration, ephemeral = true, bound_type = SessionToken),
 }
 
@@ -207,9 +247,8 @@ impl SessionToken {
 }
 
 impl ScopedSessionToken {
-	pub async fn from_session(db: &Db, req: &HttpRequest) -> Result<Option<Self>> {
-		let origin = get_request_origin(req)?;
-
+	/// The scoped session of the cookie, if it's meant for the `origin` of the proxied request
+	pub async fn from_session(db: &Db, req: &HttpRequest, origin: &str) -> Result<Option<Self>> {
 		if let Some(session_id) = req.cookie(SCOPED_SESSION_COOKIE) {
 			let token = ScopedSessionToken::from_code(db, &session_id.value().to_string()).await?;
 			let metadata = token.metadata.clone().unwrap_or_default();
@@ -230,7 +269,7 @@ impl ScopedSessionToken {
 }
 
 impl ScopedSessionToken {
-	pub async fn from_proxy_cookie(db: &Db, req: &actix_web::HttpRequest) -> Result<Option<Self>> {
+	pub async fn from_proxy_cookie(db: &Db, req: &actix_web::HttpRequest, origin: &str) -> Result<Option<Self>> {
 		let cookie = req.cookie(PROXIED_COOKIE).ok_or(AppErrorKind::MissingCookieHeader)?;
 
 		let code = cookie.value().to_string();
@@ -240,7 +279,6 @@ impl ScopedSessionToken {
 		let scope_scheme = scope_parsed.scheme_str().ok_or(AppErrorKind::InvalidRedirectUri)?;
 		let scope_authority = scope_parsed.authority().ok_or(AppErrorKind::InvalidRedirectUri)?;
 		let scope_origin = format!("{}://{}", scope_scheme, scope_authority);
-		let origin = get_request_origin(req)?;
 
 		if origin != scope_origin {
 			warn!("Invalid scope for proxy cookie: {} vs {}", &origin, &scope_origin);
@@ -253,7 +291,7 @@ impl ScopedSessionToken {
 			token.bound_to.clone(),
 			Some(scope_origin)
 		).await?;
-		info!("New scoped session for: {}", &origin);
+		info!("New scoped session for: {}", origin);
 
 		Ok(Some(scoped_session))
 	}
@@ -9,8 +9,10 @@ use reindeer::Db;
 
 use crate::handle_login_action::ScopedLogin;
 use crate::oidc::handle_authorize::AuthorizeRequest;
+use crate::oidc::handle_check_session::get_browser_state;
 use crate::token::{ProxyCookieToken, SessionToken};
-use crate::{AUTHORIZATION_COOKIE, CONFIG, RANDOM_STRING_LEN, SCOPED_LOGIN, TEMPLATES};
+use crate::saml::authn_request::AuthnRequest;
+use crate::{AUTHORIZATION_COOKIE, CAS_SERVICE_COOKIE, CONFIG, RANDOM_STRING_LEN, SAML_AUTHN_REQUEST_COOKIE, SCOPED_LOGIN, TEMPLATES};
 use crate::error::{AppErrorKind, Result};
 
 pub fn get_partial(name: &str, mut data: BTreeMap<&str, String>) -> Result<String> {
@@ -31,9 +33,25 @@ pub fn get_partial(name: &str, mut data: BTreeMap<&str, String>) -> Result<Strin
 	Ok(result.clone())
 }
 
+/// The `scheme://authority` part of a URL
+pub fn get_url_origin(url: &str) -> Option<String> {
+	// Only the origin is parsed, the rest might contain characters that `Uri` rejects
+	let (scheme, rest) = url.split_once("://")?;
+	let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+	let uri = format!("{}://{}", scheme, authority).parse::<Uri>().ok()?;
+	Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
 pub fn get_request_origin(req: &HttpRequest) -> Result<String> {
+	// ingress-nginx `auth-url` sends the whole URL in `X-Original-URL`
+	if let Some(origin) = req.headers().get("x-original-url") {
+		log::debug!("Origin header: {:?}", origin);
+		if let Some(origin) = origin.to_str().ok().and_then(get_url_origin) {
+			return Ok(origin);
+		}
+	}
+
 	let valid_headers = [
-		header::HeaderName::from_static("x-original-url"),
 		header::ORIGIN,
 		header::REFERER,
 		// TODO: Is this correct? oauth2 proxy handles: https://github.com/oauth2-proxy/oauth2-proxy/issues/1607#issuecomment-1086889273
@@ -44,17 +62,37 @@ pub fn get_request_origin(req: &HttpRequest) -> Result<String> {
 		if let Some(origin) = req.headers().get(header) {
 			log::debug!("Origin header: {:?}", origin);
 			let Ok(origin_str) = origin.to_str() else { continue; };
-			let Ok(origin_uri) = origin_str.parse::<Uri>() else { continue; };
-			let Some(origin_scheme) = origin_uri.scheme_str() else { continue; };
-			let Some(origin_authority) = origin_uri.authority() else { continue; };
+			let Some(origin) = get_url_origin(origin_str) else { continue; };
 
-			return Ok(format!("{}://{}", origin_scheme, origin_authority));
+			return Ok(origin);
 		}
 	}
 
 	Err(AppErrorKind::MissingOriginHeader.into())
 }
 
+/// The origin of the request proxied by Traefik ForwardAuth and Caddy `forward_auth`, which split it
+/// in `X-Forwarded-Proto`, `X-Forwarded-Host` and `X-Forwarded-Uri`, or by Envoy `ext_authz`, which
+/// keeps the original `Host`. These proxies set the headers themselves but pass the rest of the
+/// client's headers along, so `X-Original-URL`, `Origin` and `Referer` can't be trusted here.
+pub fn get_forwarded_origin(req: &HttpRequest) -> Result<String> {
+	// Chained proxies append to them so the first value is the one the browser used
+	let forwarded = |name: &str| req.headers()
+		.get(name)
+		.and_then(|h| h.to_str().ok())
+		.and_then(|h| h.split(',').next())
+		.map(str::trim);
+	let forwarded_proto = forwarded("x-forwarded-proto");
+	let forwarded_host = forwarded("x-forwarded-host").or(forwarded("host"));
+
+	let (Some(proto), Some(host)) = (forwarded_proto, forwarded_host) else {
+		return Err(AppErrorKind::MissingOriginHeader.into());
+	};
+
+	log::debug!("Forwarded origin: {}://{}", proto, host);
+	get_url_origin(&format!("{}://{}", proto, host)).ok_or(AppErrorKind::MissingOriginHeader.into())
+}
+
 pub fn random_string() -> String {
 	let mut rng = StdRng::from_entropy();
 	let mut buffer = [0u8; RANDOM_STRING_LEN];
@@ -65,13 +103,23 @@ pub fn random_string() -> String {
 pub async fn get_post_login_location(db: &Db, session: &Session, user_session: &SessionToken) -> Result<String> {
 	let oidc_authorize_req_opt = session.remove_as::<AuthorizeRequest>(AUTHORIZATION_COOKIE);
 	let scoped_login_opt = session.remove_as::<ScopedLogin>(SCOPED_LOGIN);
+	let cas_service_opt = session.remove_as::<String>(CAS_SERVICE_COOKIE);
 
 	if let Some(Ok(oidc_auth_req)) = oidc_authorize_req_opt {
 		// let oidc_code = Token::new(&db, TokenKind::OIDCCode, &user, Some(user_session.code), Some(String::try_from(oidc_auth_req)?)).await?.code;
 		let oidc_code = oidc_auth_req.generate_session_code(&db, user_session.user.clone(), user_session.code.clone()).await?.code;
-		let redirect_url = oidc_auth_req.get_redirect_url(&oidc_code, &user_session.user).await.ok_or(AppErrorKind::InvalidRedirectUri)?;
+		let browser_state = get_browser_state(&user_session.code);
+		let redirect_url = oidc_auth_req.get_redirect_url(&oidc_code, &user_session.user, &browser_state).await.ok_or(AppErrorKind::InvalidRedirectUri)?;
 		log::info!("Redirecting to client {}", &oidc_auth_req.client_id);
 		Ok(redirect_url)
+	} else if let Ok(Some(_)) = session.get::<AuthnRequest>(SAML_AUTHN_REQUEST_COOKIE) {
+		// The response is posted with a form, so the SAML flow takes it from here
+		log::info!("Resuming SAML flow");
+		Ok("/saml/resume".to_string())
+	} else if let Some(Ok(service)) = cas_service_opt {
+		let redirect_url = crate::cas::issue_ticket(db, user_session, &service).await?;
+		log::info!("Redirecting to CAS service {}", &service);
+		Ok(redirect_url)
 	} else if let Some(Ok(scoped_login)) = scoped_login_opt {
 		let scoped_code = ProxyCookieToken::new(&db, user_session.user.clone(), Some(user_session.code.clone()), Some(scoped_login.clone().into())).await?.code;
 		let redirect_url = scoped_login.get_redirect_url(&scoped_code, &user_session.user).await.ok_or(AppErrorKind::InvalidRedirectUri)?;
@@ -96,6 +144,9 @@ pub mod tests {
 		let db = reindeer::open(&CONFIG.read().await.database_url).expect("Failed to open reindeer database.");
 		crate::config::ConfigKV::register(&db).expect("Failed to register config_kv entity");
 		crate::token::register_token_kind(&db).expect("Failed to register token kinds");
+		crate::oidc::dpop::DPoPProofJti::register(&db).expect("Failed to register DPoP proof store");
+		crate::oidc::consumed_code::ConsumedOIDCCode::register(&db).expect("Failed to register consumed OIDC code store");
+		crate::oidc::ciba::CIBARequest::register(&db).expect("Failed to register CIBA request store");
 
 		db
 	}
@@ -1,10 +1,11 @@
 use actix_session::Session;
 use actix_web::web::Json;
-use actix_web::{post, web};
+use actix_web::{post, web, HttpResponse};
 use serde::{Deserialize, Serialize};
 use webauthn_rs::prelude::*;
 
-use crate::error::{AppErrorKind, Result};
+use crate::error::{AppErrorKind, Response};
+use crate::oidc::handle_check_session::{get_browser_state, get_browser_state_cookie};
 use crate::token::{SessionToken, WebauthnToken};
 use crate::utils::get_post_login_location;
 use crate::SESSION_COOKIE;
@@ -17,7 +18,7 @@ pub struct AuthFinishResponse {
 }
 
 #[post("/webauthn/auth/finish")]
-pub async fn auth_finish(session: Session, db: web::Data<reindeer::Db>, webauthn: web::Data<Webauthn>, req: Json<PublicKeyCredential>) -> Result<Json<AuthFinishResponse>> {
+pub async fn auth_finish(session: Session, db: web::Data<reindeer::Db>, webauthn: web::Data<Webauthn>, req: Json<PublicKeyCredential>) -> Response {
 	// Since we trust the registration token and it holds the user, we treat it as an authentication token as well
 	let auth_code = session.remove_as::<String>(WEBAUTHN_COOKIE).ok_or(AppErrorKind::TokenNotFound)??;
 	let auth_token = WebauthnToken::from_code(&db, &auth_code).await?;
@@ -35,7 +36,9 @@ pub async fn auth_finish(session: Session, db: web::Data<reindeer::Db>, webauthn
 
 	// TODO: Handle the passkey store counter
 
-	Ok(Json(AuthFinishResponse {
-		redirect_to: redirect_url,
-	}))
+	Ok(HttpResponse::Ok()
+		.cookie(get_browser_state_cookie(get_browser_state(&user_session.code)))
+		.json(AuthFinishResponse {
+			redirect_to: redirect_url,
+		}))
 }
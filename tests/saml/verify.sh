@@ -0,0 +1,51 @@
+#!/bin/sh
+# Verifies the enveloped signature of assertion.xml without MagicEntry's code:
+# libxml2 (xmllint --exc-c14n) canonicalizes and the openssl CLI digests and verifies.
+# The Test workflow runs it, run it from this directory as well after regenerating the fixture.
+set -eu
+
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+# Enveloped signature transform: the assertion without its ds:Signature
+python3 - "$tmp" <<'PY'
+import sys
+from xml.dom import minidom
+
+DS = "http://www.w3.org/2000/09/xmldsig#"
+doc = minidom.parse("assertion.xml")
+signature = doc.getElementsByTagNameNS(DS, "Signature")[0]
+signed_info = signature.getElementsByTagNameNS(DS, "SignedInfo")[0].cloneNode(True)
+signature_value = signature.getElementsByTagNameNS(DS, "SignatureValue")[0].firstChild.data
+
+with open(sys.argv[1] + "/signed_info.xml", "w") as f:
+	# Exclusive C14N renders the inherited ds namespace on the apex of the subset
+	signed_info.setAttribute("xmlns:ds", DS)
+	f.write(signed_info.toxml())
+
+signature.parentNode.removeChild(signature)
+with open(sys.argv[1] + "/unsigned.xml", "w") as f:
+	f.write(doc.documentElement.toxml())
+
+with open(sys.argv[1] + "/digest.txt", "w") as f:
+	f.write(signed_info.getElementsByTagNameNS(DS, "DigestValue")[0].firstChild.data)
+
+with open(sys.argv[1] + "/signature.b64", "w") as f:
+	f.write(signature_value)
+PY
+
+xmllint --exc-c14n "$tmp/unsigned.xml" > "$tmp/unsigned.c14n"
+xmllint --exc-c14n "$tmp/signed_info.xml" > "$tmp/signed_info.c14n"
+
+digest=$(openssl dgst -sha256 -binary "$tmp/unsigned.c14n" | openssl base64 -A)
+if [ "$digest" = "$(cat "$tmp/digest.txt")" ]; then
+	echo "DigestValue: OK"
+else
+	echo "DigestValue: FAIL"
+	exit 1
+fi
+
+openssl base64 -d -A -in "$tmp/signature.b64" > "$tmp/signature.bin"
+openssl x509 -in certificate.pem -pubkey -noout > "$tmp/public.pem"
+printf "SignatureValue: "
+openssl dgst -sha256 -verify "$tmp/public.pem" -signature "$tmp/signature.bin" "$tmp/signed_info.c14n"